@@ -0,0 +1,190 @@
+use std::io::{self, Write};
+
+use crate::machine::operations::{standard_operations, OperationRegistry};
+use crate::machine::parser::parse_value;
+use crate::machine::{Machine, MachineError, MachineOptions, Value};
+
+pub const USAGE: &str = "\
+usage: sicp-5-2 run <controller-file> [--machine <name>] [--reg <name>=<value>]... [--print <name>]...
+       sicp-5-2 debug <controller-file>";
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Command {
+    Run(RunOptions),
+    Debug { path: String },
+}
+
+// What `run` should do with a controller file besides running it.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct RunOptions {
+    pub path: String,
+    // The define-machine of the file to run, instead of reading it as a bare controller.
+    pub machine: Option<String>,
+    // Registers set before the run, in order.
+    pub registers: Vec<(String, Value)>,
+    // Registers printed after the run, in order.
+    pub print: Vec<String>,
+}
+
+pub fn parse_args(args: &[String]) -> Result<Command, String> {
+    match args {
+        [command, path] if command == "debug" => Ok(Command::Debug { path: path.clone() }),
+        [command, path, flags @ ..] if command == "run" => {
+            let mut options = RunOptions { path: path.clone(), ..RunOptions::default() };
+            let mut flags = flags.iter();
+            while let Some(flag) = flags.next() {
+                let argument = flags.next().ok_or_else(|| format!("{flag} expects an argument"))?;
+                match flag.as_str() {
+                    "--machine" => options.machine = Some(argument.clone()),
+                    "--reg" => {
+                        let (name, value) = argument
+                            .split_once('=')
+                            .ok_or_else(|| format!("--reg expects <name>=<value>, but got {argument}"))?;
+                        let value = parse_value(value).map_err(|e| format!("--reg {argument}: {}", e.message))?;
+                        options.registers.push((name.to_string(), value));
+                    }
+                    "--print" => options.print.push(argument.clone()),
+                    _ => return Err(format!("unknown option {flag}")),
+                }
+            }
+            Ok(Command::Run(options))
+        }
+        _ => Err(USAGE.to_string()),
+    }
+}
+
+// Build a machine from `source`, run it and print the selected registers. A bare
+// controller gets the standard operations and registers found from the text; a named
+// define-machine gets what it declares. Diagnostics and warnings go to `err`; the
+// result tells whether everything succeeded.
+pub fn run(source: &str, options: &RunOptions, out: &mut impl Write, err: &mut impl Write) -> io::Result<bool> {
+    let machine = match &options.machine {
+        Some(name) => Machine::load(source, name, &OperationRegistry::standard()),
+        None => {
+            let names: Vec<&str> = options.registers.iter().map(|(name, _)| name.as_str()).collect();
+            let machine_options = MachineOptions { auto_registers: true, ..MachineOptions::default() };
+            Machine::make_machine_with_options(&names, standard_operations(), source, machine_options)
+        }
+    };
+    let mut machine = match machine {
+        Ok(machine) => machine,
+        Err(e) => {
+            write!(err, "{}", e.render(source))?;
+            return Ok(false);
+        }
+    };
+    for warning in machine.warnings() {
+        writeln!(err, "warning: {warning}")?;
+    }
+
+    for (name, value) in &options.registers {
+        if let Err(e) = machine.set_register_contents(name, value.clone()) {
+            writeln!(err, "error: {e}")?;
+            return Ok(false);
+        }
+    }
+    if let Err(e) = machine.start() {
+        write!(err, "{}", MachineError::from(e).render(source))?;
+        return Ok(false);
+    }
+    for name in &options.print {
+        match machine.get_register_contents(name) {
+            Ok(value) => writeln!(out, "{name} = {value}")?,
+            Err(e) => {
+                writeln!(err, "error: {e}")?;
+                return Ok(false);
+            }
+        }
+    }
+    Ok(true)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{parse_args, run, Command, RunOptions};
+    use crate::machine::fixtures::GCD;
+    use crate::machine::Value;
+
+    fn args(line: &str) -> Vec<String> {
+        line.split_whitespace().map(String::from).collect()
+    }
+
+    // Whether the run succeeded, with what it wrote to out and to err.
+    fn run_source(source: &str, options: &RunOptions) -> (bool, String, String) {
+        let (mut out, mut err) = (Vec::new(), Vec::new());
+        let ok = run(source, options, &mut out, &mut err).unwrap();
+        (ok, String::from_utf8(out).unwrap(), String::from_utf8(err).unwrap())
+    }
+
+    fn run_gcd(source: &str, a: i64, b: i64) -> (bool, String, String) {
+        let options = RunOptions {
+            path: "gcd.rm".to_string(),
+            registers: vec![("a".to_string(), Value::Integer(a)), ("b".to_string(), Value::Integer(b))],
+            print: vec!["a".to_string(), "t".to_string()],
+            ..RunOptions::default()
+        };
+        run_source(source, &options)
+    }
+
+    #[test]
+    fn test_parse_args() {
+        assert_eq!(parse_args(&args("run gcd.rm --reg a=206 --reg b=40 --print a")), Ok(Command::Run(RunOptions {
+            path: "gcd.rm".to_string(),
+            registers: vec![("a".to_string(), Value::Integer(206)), ("b".to_string(), Value::Integer(40))],
+            print: vec!["a".to_string()],
+            ..RunOptions::default()
+        })));
+        assert_eq!(parse_args(&args("run machines.rm --machine gcd")), Ok(Command::Run(RunOptions {
+            path: "machines.rm".to_string(),
+            machine: Some("gcd".to_string()),
+            ..RunOptions::default()
+        })));
+        assert_eq!(parse_args(&args("debug gcd.rm")), Ok(Command::Debug { path: "gcd.rm".to_string() }));
+        assert_eq!(parse_args(&args("run gcd.rm --reg a")), Err("--reg expects <name>=<value>, but got a".to_string()));
+        assert_eq!(parse_args(&args("run gcd.rm --print")), Err("--print expects an argument".to_string()));
+        assert_eq!(parse_args(&args("run gcd.rm --verbose x")), Err("unknown option --verbose".to_string()));
+        assert_eq!(parse_args(&args("run gcd.rm --reg a=(1")), Err("--reg a=(1: Expects a ')' to close this '('".to_string()));
+        assert!(parse_args(&args("gcd.rm")).is_err());
+    }
+
+    #[test]
+    fn test_run_prints_registers() {
+        assert_eq!(run_gcd(GCD, 206, 40), (true, "a = 2\nt = 0\n".to_string(), String::new()));
+    }
+
+    #[test]
+    fn test_run_reports_diagnostics() {
+        let (ok, out, err) = run_gcd(GCD, 206, 0);
+        assert!(!ok);
+        assert_eq!(out, "a = 206\n");
+        assert_eq!(err, "error: Unassigned register: t\n");
+
+        let (ok, _, err) = run_gcd(&GCD.replace("(reg a) (reg b)", "(reg a) (const 0)"), 206, 40);
+        assert!(!ok);
+        assert_eq!(err, "\
+error: Runtime error at pc 2: Operation 'rem' failed on (206 0): Division by zero
+ --> 5:9
+  |
+5 |         (assign t (op rem) (reg a) (const 0))
+  |         ^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^^
+");
+
+        let (ok, _, err) = run_gcd(&GCD.replace("(label gcd-done)", "(label done)"), 206, 40);
+        assert!(!ok);
+        assert!(err.starts_with("error: Undefined label: done\n --> 4:24\n"));
+    }
+
+    #[test]
+    fn test_run_named_machine() {
+        let source = format!("(define-machine gcd (registers a b t) (operations rem =) (controller {GCD}))");
+        let options = |name: &str, registers: &[&str]| RunOptions {
+            machine: Some(name.to_string()),
+            registers: registers.iter().map(|name| (name.to_string(), Value::Integer(12))).collect(),
+            print: vec!["a".to_string()],
+            ..RunOptions::default()
+        };
+        assert_eq!(run_source(&source, &options("gcd", &["a", "b"])), (true, "a = 12\n".to_string(), String::new()));
+        assert_eq!(run_source(&source, &options("gcd", &["a", "n"])), (false, String::new(), "error: Unknown register: n\n".to_string()));
+        assert_eq!(run_source(&source, &options("fib", &[])), (false, String::new(), "error: Unknown machine: fib\n".to_string()));
+    }
+}
@@ -0,0 +1,294 @@
+use std::io::{self, BufRead, Write};
+
+use crate::machine::parser::parse_value;
+use crate::machine::{Halted, Machine, MemoryOutput, MemorySink, RuntimeError, Step, Value};
+
+const HELP: &str = "\
+commands:
+  step                   execute one instruction
+  continue               run until a breakpoint or the end
+  break <label> <offset> stop before the offset-th instruction after label
+  regs                   show every register
+  set <reg> <value>      assign a constant to a register
+  stack                  show the stack, bottom to top
+  trace on|off           report each instruction as it runs
+  where                  show the instruction at pc
+  help                   show this list
+  quit                   leave the debugger";
+
+// A command loop driving a Machine, reading commands from any BufRead and
+// answering on any Write, so a session can be scripted as well as typed.
+pub struct Debugger {
+    machine: Machine,
+    trace: MemorySink,
+    printed: MemoryOutput,
+}
+
+impl Debugger {
+    pub fn new(mut machine: Machine) -> Self {
+        // Trace lines and what the machine prints are collected and written out with
+        // the answer to each command.
+        let trace = MemorySink::default();
+        machine.set_trace_sink(Box::new(trace.clone()));
+        let printed = MemoryOutput::default();
+        machine.set_output(Box::new(printed.clone()));
+        Debugger { machine, trace, printed }
+    }
+
+    pub fn machine(&self) -> &Machine {
+        &self.machine
+    }
+
+    // Read commands until quit or the end of the input. The input becomes the
+    // machine's, so (op read) takes the next line of the session.
+    pub fn run(&mut self, input: impl BufRead + 'static, output: &mut impl Write) -> io::Result<()> {
+        self.machine.set_input(Box::new(input));
+        loop {
+            write!(output, "(rm) ")?;
+            output.flush()?;
+            let mut line = String::new();
+            if self.machine.input().read_line(&mut line)? == 0 || !self.command(line.trim_end_matches(['\n', '\r']), output)? {
+                break;
+            }
+        }
+        writeln!(output)
+    }
+
+    // Execute one command line, returning false when the session is over.
+    pub fn command(&mut self, line: &str, output: &mut impl Write) -> io::Result<bool> {
+        let words: Vec<&str> = line.split_whitespace().collect();
+        match words.as_slice() {
+            [] => {}
+            ["step" | "s"] => {
+                let step = self.machine.step();
+                self.write_trace(output)?;
+                match step {
+                    Ok(Step::Halted) => writeln!(output, "halted")?,
+                    Ok(_) => self.write_where(output)?,
+                    Err(e) => write_runtime_error(&e, output)?,
+                }
+            }
+            ["continue" | "c"] => {
+                let halted = self.machine.proceed();
+                self.write_trace(output)?;
+                match halted {
+                    Ok(Halted::Done) => writeln!(output, "halted")?,
+                    Ok(Halted::Breakpoint(_)) => {
+                        write!(output, "breakpoint: ")?;
+                        self.write_where(output)?;
+                    }
+                    Ok(Halted::StepLimit(steps)) => writeln!(output, "stopped after {steps} instructions")?,
+                    Err(e) => write_runtime_error(&e, output)?,
+                }
+            }
+            ["break" | "b", label, offset] => match offset.parse() {
+                Ok(offset) => match self.machine.set_breakpoint(label, offset) {
+                    Ok(()) => writeln!(output, "breakpoint set at {label} {offset}")?,
+                    Err(e) => writeln!(output, "error: {e}")?,
+                },
+                Err(_) => writeln!(output, "error: offset must be a number, but got {offset}")?,
+            },
+            ["regs"] => {
+                let mut names = vec!["pc", "flag"];
+                names.extend(self.machine.register_names());
+                for name in names {
+                    match self.machine.get_register_contents(name) {
+                        Ok(value) => writeln!(output, "{name} = {value}")?,
+                        Err(_) => writeln!(output, "{name} = *unassigned*")?,
+                    }
+                }
+            }
+            ["set", reg, value @ ..] => match parse_value(&value.join(" ")).map_err(|e| e.message) {
+                Ok(value) => match self.machine.set_register_contents(reg, value) {
+                    Ok(()) => writeln!(output, "{reg} = {}", self.machine.get_register_contents(reg).unwrap())?,
+                    Err(e) => writeln!(output, "error: {e}")?,
+                },
+                Err(message) => writeln!(output, "error: {message}")?,
+            },
+            ["stack"] if self.machine.register_stacks().is_empty() => {
+                let values = self.machine.stack().contents();
+                if values.is_empty() {
+                    writeln!(output, "stack is empty")?;
+                } else {
+                    writeln!(output, "stack (bottom to top): {}", join(&values))?;
+                }
+            }
+            ["stack"] => {
+                // With a stack per register only the ones holding something are shown.
+                let mut empty = true;
+                for (name, stack) in self.machine.register_stacks() {
+                    let values = stack.contents();
+                    if !values.is_empty() {
+                        writeln!(output, "stack of {name} (bottom to top): {}", join(&values))?;
+                        empty = false;
+                    }
+                }
+                if empty {
+                    writeln!(output, "stacks are empty")?;
+                }
+            }
+            ["trace", "on"] => {
+                self.machine.trace_on();
+                writeln!(output, "trace on")?;
+            }
+            ["trace", "off"] => {
+                self.machine.trace_off();
+                writeln!(output, "trace off")?;
+            }
+            ["where" | "w"] => self.write_where(output)?,
+            ["help" | "h"] => writeln!(output, "{HELP}")?,
+            ["quit" | "q"] => return Ok(false),
+            _ => writeln!(output, "error: unknown command '{}', try help", line.trim())?,
+        }
+        Ok(true)
+    }
+
+    fn write_where(&self, output: &mut impl Write) -> io::Result<()> {
+        match self.machine.location() {
+            Some(location) => writeln!(output, "{location}"),
+            None => writeln!(output, "pc {}: past the last instruction", self.machine.pc()),
+        }
+    }
+
+    fn write_trace(&self, output: &mut impl Write) -> io::Result<()> {
+        for line in self.trace.take() {
+            writeln!(output, "trace: {line}")?;
+        }
+        write!(output, "{}", self.printed.take())
+    }
+}
+
+fn join(values: &[Value]) -> String {
+    values.iter().map(Value::to_string).collect::<Vec<_>>().join(" ")
+}
+
+fn write_runtime_error(error: &RuntimeError, output: &mut impl Write) -> io::Result<()> {
+    writeln!(output, "error: {error}")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Debugger;
+    use crate::machine::fixtures::GCD;
+    use crate::machine::operations::standard_operations;
+    use crate::machine::{Machine, MachineOptions, Value};
+
+    fn gcd() -> Machine {
+        Machine::make_machine(&["a", "b", "t"], standard_operations(), GCD).unwrap()
+    }
+
+    // Run the script on the machine, returning the answers without the prompts.
+    fn session(machine: Machine, script: &'static str) -> (Debugger, String) {
+        let mut debugger = Debugger::new(machine);
+        let mut output = Vec::new();
+        debugger.run(script.as_bytes(), &mut output).unwrap();
+        (debugger, String::from_utf8(output).unwrap().replace("(rm) ", ""))
+    }
+
+    #[test]
+    fn test_break_continue_and_inspect() {
+        let (debugger, output) = session(gcd(), "\
+set a 206
+set b 40
+break test-b 4
+continue
+regs
+where
+step
+continue
+continue
+");
+        assert_eq!(output, "\
+a = 206
+b = 40
+breakpoint set at test-b 4
+breakpoint: pc 3, test-b 4: (assign a (reg b))
+pc = #<label 3>
+flag = #f
+a = 206
+b = 40
+t = 6
+pc 3, test-b 4: (assign a (reg b))
+pc 4, test-b 5: (assign b (reg t))
+breakpoint: pc 3, test-b 4: (assign a (reg b))
+breakpoint: pc 3, test-b 4: (assign a (reg b))
+
+");
+        assert_eq!(debugger.machine().get_register_contents("a"), Ok(Value::Integer(6)));
+    }
+
+    #[test]
+    fn test_trace_stack_and_errors() {
+        let (_, output) = session(gcd(), "\
+set a 6
+set b 4
+trace on
+step
+step
+stack
+set q 1
+set pc 2
+set a (1 2)
+break nowhere 1
+jump
+quit
+step
+");
+        assert_eq!(output, "\
+a = 6
+b = 4
+trace on
+trace: test-b: (test (op =) (reg b) (const 0))
+pc 1, test-b 2: (branch (label gcd-done))
+trace: test-b: (branch (label gcd-done))
+pc 2, test-b 3: (assign t (op rem) (reg a) (reg b))
+stack is empty
+error: Unknown register: q
+error: Expected a value of kind label, but got the integer 2
+error: Expects a value, but got (1 2)
+error: Unknown label: nowhere
+error: unknown command 'jump', try help
+
+");
+    }
+
+    #[test]
+    fn test_run_to_the_end() {
+        let (debugger, output) = session(gcd(), "set a 206\nset b 40\ncontinue\nwhere\nstep\n");
+        assert!(output.ends_with("halted\npc 6: past the last instruction\nhalted\n\n"));
+        assert_eq!(debugger.machine().get_register_contents("a"), Ok(Value::Integer(2)));
+    }
+
+    #[test]
+    fn test_stack_of_each_register() {
+        let options = MachineOptions { per_register_stacks: true, ..MachineOptions::default() };
+        let controller = "(save a) (save b) (save a)";
+        let machine = Machine::make_machine_with_options(&["a", "b"], standard_operations(), controller, options).unwrap();
+        let (_, output) = session(machine, "stack\nset a 1\nset b 2\ncontinue\nstack\n");
+        assert_eq!(output, "\
+stacks are empty
+a = 1
+b = 2
+halted
+stack of a (bottom to top): 1 1
+stack of b (bottom to top): 2
+
+");
+    }
+
+    #[test]
+    fn test_printed_statistics_are_part_of_the_answer() {
+        let controller = "(save a) (perform (op print-stack-statistics))";
+        let machine = Machine::make_machine(&["a"], standard_operations(), controller).unwrap();
+        let (_, output) = session(machine, "set a 1\ncontinue\n");
+        assert_eq!(output, "a = 1\n(total-pushes = 1 maximum-depth = 1)\nhalted\n\n");
+    }
+
+    #[test]
+    fn test_read_takes_the_next_line_of_the_session() {
+        let machine = Machine::make_machine(&["a", "b"], standard_operations(), "(assign a (op read)) (assign b (reg a))").unwrap();
+        let (debugger, output) = session(machine, "step\n42\nwhere\n");
+        assert_eq!(output, "pc 1: (assign b (reg a))\npc 1: (assign b (reg a))\n\n");
+        assert_eq!(debugger.machine().get_register_contents("a"), Ok(Value::Integer(42)));
+    }
+}
@@ -1 +1,3 @@
-mod machine;
\ No newline at end of file
+pub mod cli;
+pub mod debugger;
+pub mod machine;
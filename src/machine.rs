@@ -1,99 +1,221 @@
 
-mod parser;
+mod analysis;
+mod diagnostic;
+mod error;
+#[cfg(test)]
+pub(crate) mod fixtures;
+pub mod parser;
+pub mod operations;
 mod procedure;
-use parser::{Expr, ControllerText, Instruction};
-use parser::{PrimitiveExpr, ValueExpr};
-use parser::parse;
+mod trace;
+mod value;
+pub use analysis::DataPaths;
+pub use diagnostic::Span;
+pub use error::{AssembleError, AssembleWarning, MachineError, ParseError, RuntimeError, RuntimeErrorKind};
+use parser::{Expr, ControllerText, Instruction, InstructionKind};
+use parser::{Label, PrimitiveExpr, ValueExpr};
+use parser::{parse, parse_definitions, MachineDefinition};
+use operations::OperationRegistry;
 
-use std::vec;
-use std::{cell::RefCell, collections::HashMap};
+use std::{cell::{Cell, RefCell}, collections::{BTreeMap, BTreeSet, HashMap}};
+use std::fmt;
+use std::io::{self, BufRead, BufReader, Write};
 use std::rc::Rc;
 
 use crate::machine::parser::OpreationExpr;
+pub use procedure::{Arity, Executor, CloneExecutor, IntoOperation, Operation, OperationMarker, Signature, op};
+pub use trace::{MemoryOutput, MemorySink, TraceEvent, TraceSink, WriteSink};
+pub use value::{FromValue, IntoValues, OperandKind, Value, ValueKind};
+use procedure::{MachineInstruction, Next, Procedure, ValueProcedure, combine_procedures};
 
-#[derive(Debug)]
-struct Register {
-    contents: RefCell<Option<u32>>,
+// A register is a handle: every clone shares the same contents, so the procedures
+// built by assemble read and write the very register that lives in register_table.
+#[derive(Debug, Clone)]
+pub struct Register {
+    name: Rc<str>,
+    contents: Rc<RefCell<Option<Value>>>,
+    traced: Rc<Cell<bool>>,
 }
 impl Register {
-    fn make_register() -> Self {
-        Register { contents: RefCell::new(None) }
+    fn make_register(name: &str) -> Self {
+        Register { name: name.into(), contents: Rc::new(RefCell::new(None)), traced: Rc::new(Cell::new(false)) }
     }
-    fn get_content(&self) -> u32 {
-        self.contents.borrow().unwrap()
+    pub fn name(&self) -> &str {
+        &self.name
     }
-    fn set_content(&self, value: u32) {
+    fn get_content(&self) -> Option<Value> {
+        self.contents.borrow().clone()
+    }
+    fn set_content(&self, value: Value) {
         self.contents.replace(Some(value));
     }
-}
-impl Clone for Register {
-    fn clone(&self) -> Self {
-        Register { contents: self.contents.clone() }
+    // Read the register when the instruction runs, failing if nothing was ever assigned.
+    fn read(&self) -> Result<Value, RuntimeErrorKind> {
+        self.get_content().ok_or_else(|| RuntimeErrorKind::UnassignedRegister(self.name.to_string()))
+    }
+    // Whether the value may be written to the register: the pc only takes labels.
+    fn check(&self, value: &Value) -> Result<(), RuntimeErrorKind> {
+        if &*self.name == "pc" {
+            value.as_label()?;
+        }
+        Ok(())
     }
 }
 
-trait Executor: CloneExecutor {
-    type Oprands;
+// Operations hand back a list of values, but only the first one is used as the result.
+fn first_value(values: Vec<Value>) -> Result<Value, RuntimeErrorKind> {
+    values.into_iter().next().ok_or(RuntimeErrorKind::NoValue)
+}
 
-    fn execute(&self, machine: &mut Machine, oprands: Self::Oprands) -> Vec<u32>;
+// Every label an instruction refers to, in the order they appear.
+fn label_references(insts: &[Instruction]) -> Vec<&Label> {
+    fn in_value_expr(expr: &ValueExpr) -> Vec<&Label> {
+        match expr {
+            ValueExpr::PrimitiveExpr(PrimitiveExpr::Label(label)) => vec![label],
+            ValueExpr::PrimitiveExpr(_) => Vec::new(),
+            ValueExpr::OpreationExpr(op) => op.oprands().iter().flat_map(in_value_expr).collect(),
+        }
+    }
+    insts
+        .iter()
+        .flat_map(|inst| match inst.kind() {
+            InstructionKind::Assign { val_expr, .. } => in_value_expr(val_expr),
+            InstructionKind::Test(op) | InstructionKind::Perform(op) => {
+                op.oprands().iter().flat_map(in_value_expr).collect()
+            }
+            InstructionKind::Branch(label) | InstructionKind::Goto(PrimitiveExpr::Label(label)) => vec![label],
+            InstructionKind::Goto(_) | InstructionKind::Save { .. } | InstructionKind::Restore { .. } => Vec::new(),
+        })
+        .collect()
 }
 
-trait CloneExecutor {
-    fn clone_box(&self) -> Box<dyn Executor<Oprands = Vec<u32>>>;
+// How a run of the machine came to an end.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Halted {
+    // The pc fell off the end of the instruction sequence.
+    Done,
+    // The configured step limit was reached before the controller finished.
+    StepLimit(usize),
+    // Stopped before executing the instruction at this pc, which has a breakpoint.
+    Breakpoint(usize),
 }
-impl<T> CloneExecutor for T
-where
-    T: 'static + Executor<Oprands = Vec<u32>> + Clone,
-{
-    fn clone_box(&self) -> Box<dyn Executor<Oprands = Vec<u32>>> {
-        Box::new(self.clone())
+
+// An instruction of the controller, found by label and offset like a breakpoint:
+// the first instruction after the label has offset 1.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Location {
+    pub pc: usize,
+    pub label: Option<String>,
+    pub offset: usize,
+    pub instruction: String,
+}
+impl fmt::Display for Location {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.label {
+            Some(label) => write!(f, "pc {}, {label} {}: {}", self.pc, self.offset, self.instruction),
+            None => write!(f, "pc {}: {}", self.pc, self.instruction),
+        }
     }
 }
 
-type Operation = Box<dyn Executor<Oprands = Vec<u32>>>;
-impl Clone for Operation {
-    fn clone(&self) -> Self {
-        self.clone_box()
-    }
+// What a single step of the machine did.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Step {
+    // The instruction at pc was executed and the pc moved on to the next one.
+    Advanced { pc: usize },
+    // The instruction at pc was a goto or a taken branch to target.
+    Jumped { pc: usize, target: usize },
+    // There was no instruction left to execute.
+    Halted,
 }
-type Procedure = Box<dyn Fn(&mut Machine)>;
-type ValueProcedure = Box<dyn Fn(&mut Machine) -> Vec<u32>>;
-type Bool = u32;
-fn combine_procedures(procedures: Vec<ValueProcedure>) -> Result<ValueProcedure, String> {
-    Ok(Box::new(move |machine: &mut Machine| {
-        procedures
-            .iter()
-            .flat_map(|proc| proc(machine))
-            .collect()
-    }))
+
+// Choices made when building a machine; the default is the machine of SICP 5.2.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct MachineOptions {
+    // Allocate a register on its first reference in the controller (SICP exercise 5.13)
+    // instead of failing on a name missing from the register list.
+    pub auto_registers: bool,
+    // Give every register a stack of its own (SICP exercise 5.11c), so that save and
+    // restore of different registers never interfere.
+    pub per_register_stacks: bool,
+    // Make restore fail unless the top of the stack was saved from the same register
+    // (SICP exercise 5.11b).
+    pub checked_restore: bool,
 }
 
 #[derive(Default)]
-// Error type isn't assigned yet.
 // The register_table includes two special registers 'pc' and 'flag'.
-struct Machine {
+pub struct Machine {
     register_table: HashMap<String, Register>,
     the_operations: HashMap<String, Operation>,
     stack: Stack,
-    the_instruction_sequence: Vec<Procedure>,
+    // The stack of each register with per_register_stacks, empty otherwise.
+    register_stacks: BTreeMap<String, Stack>,
+    the_instruction_sequence: Vec<MachineInstruction>,
+    step_limit: Option<usize>,
+    warnings: Vec<AssembleWarning>,
+    options: MachineOptions,
+    // How many times each instruction was executed since the last reset_stats.
+    executions: Vec<usize>,
+    tracing: bool,
+    trace_sink: Option<Box<dyn TraceSink>>,
+    // Where operations like read take their input and print and print-stack-statistics
+    // print, stdin and stdout unless set.
+    input: Option<Box<dyn BufRead>>,
+    output: Option<Box<dyn Write>>,
+    label_table: HashMap<String, usize>,
+    // The registers the controller refers to, found while assembling.
+    referenced_registers: BTreeSet<String>,
+    breakpoints: BTreeSet<usize>,
+    // The breakpoint the last run stopped at, which proceeding goes past.
+    stopped_at: Option<usize>,
 }
 impl Machine {
-    pub fn make_machine(register_names: &[&str], ops: Vec<(String, Operation)>, controller_text: &str) -> Result<Self, String> {
-        let mut machine = Machine::default();
-        machine.register_table.insert("pc".to_string(), Register::make_register());
-        machine.register_table.insert("flag".to_string(), Register::make_register());
+    pub fn make_machine(register_names: &[&str], ops: Vec<(String, Operation)>, controller_text: &str) -> Result<Self, MachineError> {
+        Self::make_machine_with_options(register_names, ops, controller_text, MachineOptions::default())
+    }
+    pub fn make_machine_with_options(
+        register_names: &[&str],
+        ops: Vec<(String, Operation)>,
+        controller_text: &str,
+        options: MachineOptions,
+    ) -> Result<Self, MachineError> {
+        let (_, text) = parse(controller_text)?;
+        Self::build(register_names, ops, text, options)
+    }
+    // Make the machine of a define-machine form, binding its operations through the registry.
+    pub fn from_definition(definition: MachineDefinition, registry: &OperationRegistry) -> Result<Self, MachineError> {
+        let registers: Vec<&str> = definition.registers.iter().map(String::as_str).collect();
+        let ops = registry.resolve(&definition.operations)?;
+        Self::build(&registers, ops, definition.controller, MachineOptions::default())
+    }
+    // Make the machine called `name` out of the define-machine forms in `source`.
+    pub fn load(source: &str, name: &str, registry: &OperationRegistry) -> Result<Self, MachineError> {
+        let definition = parse_definitions(source)?
+            .into_iter()
+            .find(|definition| definition.name == name)
+            .ok_or_else(|| AssembleError::UnknownMachine(name.to_string()))?;
+        Self::from_definition(definition, registry)
+    }
+    fn build(
+        register_names: &[&str],
+        ops: Vec<(String, Operation)>,
+        text: ControllerText,
+        options: MachineOptions,
+    ) -> Result<Self, MachineError> {
+        let mut machine = Machine { options, ..Machine::default() };
+        machine.allocate_register("pc");
+        machine.allocate_register("flag");
         for name in register_names {
             machine.allocate_register(name);
         }
+        // Like make-new-machine, every machine has the stack and trace operations; ops can override them.
+        machine.install_operations(operations::machine_operations());
         machine.install_operations(ops);
 
-        parse(controller_text)
-            .map_err(|e| format!("Parsing controller text error: {e}"))
-            .and_then(move |(_, text)|{
-                let instructions = machine.assemble(text)?;
-                machine.install_instruction_sequence(instructions);
-                Ok(machine)
-            })
+        let instructions = machine.assemble(text)?;
+        machine.install_instruction_sequence(instructions);
+        Ok(machine)
     }
     
 // assemble is used before install_sequences in make_machine, so assemble should be added to impl Machine
@@ -102,17 +224,46 @@ impl Machine {
 // In the original text, Procedure is the process to be executed, constructing Instruction as a pair (text, proc),
 // When execute is called, it directly runs (cdr Instruction), so assembly must be called before running to generate procs in insts
 // During assembly, instructions are written via (set-cdr! inst)
-    fn assemble(&mut self, controller_text: ControllerText) -> Result<Vec<Procedure>, String> {
+    fn assemble(&mut self, controller_text: ControllerText) -> Result<Vec<MachineInstruction>, AssembleError> {
         let mut insts = Vec::new();
         let mut label_table = HashMap::new();
 
-        self.extract_labels(controller_text, &mut insts, &mut label_table);
-        
+        let definitions = self.extract_labels(controller_text, &mut insts, &mut label_table)?;
+
+        // Check every label reference up front, so all the undefined ones are reported together.
+        let references = label_references(&insts);
+        let undefined: Vec<Label> = references
+            .iter()
+            .filter(|label| !label_table.contains_key(&label.get_name()))
+            .map(|label| (*label).clone())
+            .collect();
+        if !undefined.is_empty() {
+            return Err(AssembleError::UndefinedLabels(undefined));
+        }
+        // The region of an instruction is the last label defined at or before it.
+        let mut starts = definitions.iter().map(|label| (label_table[&label.get_name()], label.get_name())).peekable();
+        let mut region = None;
+        let mut regions = Vec::with_capacity(insts.len());
+        for index in 0..insts.len() {
+            while let Some((_, name)) = starts.next_if(|(start, _)| *start <= index) {
+                region = Some(name);
+            }
+            regions.push(region.clone());
+        }
+
+        self.warnings = definitions
+            .into_iter()
+            .filter(|label| !references.iter().any(|reference| reference.get_name() == label.get_name()))
+            .map(AssembleWarning::UnusedLabel)
+            .collect();
+
         let mut procedures = Vec::new();
-        for inst in insts {
-            let proc = self.make_exec_proc(inst, &label_table)?;
-            procedures.push(proc);
+        for (inst, label) in insts.into_iter().zip(regions) {
+            let proc = self.make_exec_proc(&inst, &label_table)?;
+            procedures.push(MachineInstruction { text: inst, label, proc });
         }
+        // Kept so breakpoints can be set relative to labels.
+        self.label_table = label_table;
     
         Ok(procedures)
     }
@@ -122,45 +273,106 @@ impl Machine {
 // The parameters (insts, labels) in update_insts are continuously applied to lambda and cons-connected into a complete list according to the definition
 // Key concept search: "continuation" procedure
 //     Here this technique will be replaced simply by Rust iteration
-    fn extract_labels(&mut self, text: ControllerText, insts: &mut Vec<Instruction>, labels: &mut HashMap<String, usize>) {
-        text.into_iter().for_each(|expr| {
+// Unlike the original, a label defined twice is an error instead of silently shadowing (ex 5.8).
+// The label definitions are returned in order.
+    fn extract_labels(&mut self, text: ControllerText, insts: &mut Vec<Instruction>, labels: &mut HashMap<String, usize>) -> Result<Vec<Label>, AssembleError> {
+        let mut definitions: Vec<Label> = Vec::new();
+        for expr in text {
             match expr {
                 Expr::Instruction(instruction) => {
                     insts.push(instruction);
                 }
                 Expr::Label(label) => {
+                    if let Some(first) = definitions.iter().find(|defined| defined.get_name() == label.get_name()) {
+                        return Err(AssembleError::DuplicateLabel {
+                            name: label.get_name(),
+                            first: first.span(),
+                            second: label.span(),
+                        });
+                    }
                     labels.insert(label.get_name(), insts.len());
+                    definitions.push(label);
                 }
             }
-        });  
+        }
+        Ok(definitions)
     }
         
 // update_insts: iterate through instructions
-    fn make_exec_proc(&mut self, instruction: Instruction, labels: &HashMap<String, usize>) -> Result<Procedure, String> {
+    fn make_exec_proc(&mut self, instruction: &Instruction, labels: &HashMap<String, usize>) -> Result<Procedure, AssembleError> {
         
-        match instruction {
-            Instruction::Assign { target_reg, val_expr } => {
-                let target = self.get_register(&target_reg)?.clone();
-                let exec_val_expr = self.make_val_expr_exec(&val_expr, labels)?;
-                Ok(Box::new(move |machine: &mut Machine| {
-                    let value = exec_val_expr(machine)[0];
-                    target.set_content(value);
+        match instruction.kind() {
+            InstructionKind::Assign { target_reg, val_expr } => {
+                let target = self.assembly_register(target_reg)?.clone();
+                let exec_val_expr = self.make_val_expr_exec(val_expr, labels)?;
+                Ok(Rc::new(move |machine: &mut Machine| {
+                    let value = first_value(exec_val_expr(machine)?)?;
+                    machine.write_register(&target, value)?;
+                    Ok(Next::Advance)
                 }))
             }
-            Instruction::Branch(label) => {
+            InstructionKind::Branch(label) => {
                 let label_name = label.get_name();
                 let target_pc = *labels
                     .get(&label_name)
-                    .ok_or_else(|| format!("Label '{}' not found", label_name))?;
-                Ok(Box::new(move |machine: &mut Machine| {
-                    machine.set_pc(target_pc as u32);
+                    .ok_or_else(|| AssembleError::UndefinedLabels(vec![label.clone()]))?;
+                Ok(Rc::new(move |machine: &mut Machine| {
+                    if machine.get_register_contents("flag")?.is_true() {
+                        Ok(Next::Jump(target_pc))
+                    } else {
+                        Ok(Next::Advance)
+                    }
                 }))
             }
-            Instruction::Test(cond) => {
-                let condition = self.make_operation_exec(&cond, labels)?;
-                Ok(Box::new(move |machine: &mut Machine| {
-                    let new_flag = condition(machine)[0];
+            InstructionKind::Test(cond) => {
+                let condition = self.make_operation_exec(cond, labels)?;
+                Ok(Rc::new(move |machine: &mut Machine| {
+                    let new_flag = first_value(condition(machine)?)?;
                     machine.set_flag(new_flag);
+                    Ok(Next::Advance)
+                }))
+            }
+            InstructionKind::Goto(PrimitiveExpr::Label(label)) => {
+                let label_name = label.get_name();
+                let target_pc = *labels
+                    .get(&label_name)
+                    .ok_or_else(|| AssembleError::UndefinedLabels(vec![label.clone()]))?;
+                Ok(Rc::new(move |_machine: &mut Machine| Ok(Next::Jump(target_pc))))
+            }
+            InstructionKind::Goto(PrimitiveExpr::Register(reg)) => {
+                let reg = self.assembly_register(reg)?.clone();
+                Ok(Rc::new(move |_machine: &mut Machine| {
+                    let target_pc = reg.read()?.as_label()?;
+                    Ok(Next::Jump(target_pc))
+                }))
+            }
+            InstructionKind::Goto(dest @ PrimitiveExpr::Constant(_)) => {
+                Err(AssembleError::InvalidDestination(dest.to_string()))
+            }
+            InstructionKind::Save { reg } => {
+                let reg = self.assembly_register(reg)?.clone();
+                let stack = self.stack_of(&reg);
+                Ok(Rc::new(move |_: &mut Machine| {
+                    let value = reg.read()?;
+                    stack.save(&reg, value);
+                    Ok(Next::Advance)
+                }))
+            }
+            InstructionKind::Restore { reg } => {
+                let reg = self.assembly_register(reg)?.clone();
+                let stack = self.stack_of(&reg);
+                let checked = self.options.checked_restore;
+                Ok(Rc::new(move |machine: &mut Machine| {
+                    let value = stack.restore(&reg, checked)?;
+                    machine.write_register(&reg, value)?;
+                    Ok(Next::Advance)
+                }))
+            }
+            InstructionKind::Perform(op) => {
+                let action = self.make_operation_exec(op, labels)?;
+                Ok(Rc::new(move |machine: &mut Machine| {
+                    action(machine)?;
+                    Ok(Next::Advance)
                 }))
             }
         }
@@ -168,17 +380,17 @@ impl Machine {
     }
 
     //We have to make sure the operation error to be handled while assembling 
-    fn make_val_expr_exec(&mut self, expr: &ValueExpr, labels: &HashMap<String, usize>) -> Result<ValueProcedure, String> {
+    fn make_val_expr_exec(&mut self, expr: &ValueExpr, labels: &HashMap<String, usize>) -> Result<ValueProcedure, AssembleError> {
         match expr {
             ValueExpr::OpreationExpr(op) => {
-                let proc = self.make_operation_exec(&op, labels)?;
+                let proc = self.make_operation_exec(op, labels)?;
                     Ok(Box::new(move |machine: &mut Machine| {
                         proc(machine)
                     }))
                 }
                 ValueExpr::PrimitiveExpr(PrimitiveExpr::Constant(value)) => {
-                    let value = *value;
-                    Ok(Box::new(move |_machine: &mut Machine| vec![value]))
+                    let value = value.clone();
+                    Ok(Box::new(move |_machine: &mut Machine| Ok(vec![value.clone()])))
                 }
                 ValueExpr::PrimitiveExpr(PrimitiveExpr::Label(label)) => {
                     let label_name = label.get_name();
@@ -186,32 +398,50 @@ impl Machine {
                         .get(&label_name)
                         .cloned()
                         .map(|index| {
-                            Box::new(move |_machine: &mut Machine| vec![index as u32]) as ValueProcedure
+                            Box::new(move |_machine: &mut Machine| Ok(vec![Value::Label(index)])) as ValueProcedure
                         })
-                        .ok_or_else(|| format!("Label '{}' not found", label_name))
+                        .ok_or_else(|| AssembleError::UndefinedLabels(vec![label.clone()]))
                 }
                 ValueExpr::PrimitiveExpr(PrimitiveExpr::Register(reg)) => {
-                    let reg = self.get_register(reg)?;
-                    let contents = reg.get_content();
-                    Ok(Box::new(move |_machine: &mut Machine| vec![contents]))
+                    // Only the handle is captured here, the contents are read at run time.
+                    let reg = self.assembly_register(reg)?.clone();
+                    Ok(Box::new(move |_machine: &mut Machine| Ok(vec![reg.read()?])))
                 }
         }
     }
 
-    fn make_operation_exec(&mut self, op: &OpreationExpr, labels: &HashMap<String, usize>) -> Result<ValueProcedure, String> {
-        if op.oprands().len() != op.arity() {
-            return Err(format!(
-                "Operation '{}' expects {} operands, but got {}",
-                op.name(),
-                op.arity(),
-                op.oprands().len()
-            ));
+    fn make_operation_exec(&mut self, op: &OpreationExpr, labels: &HashMap<String, usize>) -> Result<ValueProcedure, AssembleError> {
+        let operation = self.get_operation(op.name())?;
+        let signature = operation.signature();
+        if !signature.arity.accepts(op.oprands().len()) {
+            return Err(AssembleError::Arity {
+                operation: op.name().to_string(),
+                expected: signature.arity,
+                found: op.oprands().len(),
+            });
+        }
+        // Only constants and labels have a kind known before running (ex 5.9).
+        for (position, oprand) in op.oprands().iter().enumerate() {
+            let found = match oprand {
+                ValueExpr::PrimitiveExpr(PrimitiveExpr::Constant(value)) => value.kind(),
+                ValueExpr::PrimitiveExpr(PrimitiveExpr::Label(_)) => ValueKind::Label,
+                _ => continue,
+            };
+            let expected = signature.operand(position);
+            if !expected.accepts(found) {
+                return Err(AssembleError::OperandKind {
+                    operation: op.name().to_string(),
+                    position: position + 1,
+                    expected,
+                    found,
+                });
+            }
         }
 
         // 1. Convert all operands to Procedures
-        let procedures: Result<Vec<ValueProcedure>, String> = op
+        let procedures: Result<Vec<ValueProcedure>, AssembleError> = op
             .oprands()
-            .into_iter()
+            .iter()
             .map(|val_expr| self.make_val_expr_exec(val_expr, labels))
             .collect();
         let procedures = procedures?;
@@ -219,64 +449,362 @@ impl Machine {
         // 2. Combine all Procedures into a single Procedure
         let oprands_proc = combine_procedures(procedures)?;
 
-        // 3. Get the operation
-        let operation = self.get_operation(op.name())?.clone();
-        
-        // 4. Generate the final closure
+        // 3. Generate the final closure
+        // The operands are kept so a failure can show what the operation was applied to.
+        let name = op.name().to_string();
         Ok(Box::new(move |machine: &mut Machine| {
-            let oprands = oprands_proc(machine);
-            operation.execute(machine, oprands)
+            let oprands = oprands_proc(machine)?;
+            operation.execute(machine, oprands.clone()).map_err(|cause| RuntimeErrorKind::OperationFailed {
+                operation: name.clone(),
+                operands: oprands,
+                cause: Box::new(cause),
+            })
         }))
     }
 }
 
 impl Machine {
-    fn install_instruction_sequence(&mut self, seq: Vec<Procedure>) {
+    fn install_instruction_sequence(&mut self, seq: Vec<MachineInstruction>) {
+        self.executions = vec![0; seq.len()];
         self.the_instruction_sequence = seq;
     }
     fn allocate_register(&mut self, name: &str) {
-        self.register_table.insert(name.to_string(), Register::make_register());
+        self.register_table.insert(name.to_string(), Register::make_register(name));
+        if self.options.per_register_stacks && !matches!(name, "pc" | "flag") {
+            self.register_stacks.insert(name.to_string(), Stack::make_stack());
+        }
+    }
+    // The stack that save and restore of the register use. With per_register_stacks,
+    // pc and flag only get one once the controller saves or restores them.
+    fn stack_of(&mut self, reg: &Register) -> Stack {
+        if !self.options.per_register_stacks {
+            return self.stack.clone();
+        }
+        self.register_stacks.entry(reg.name().to_string()).or_insert_with(Stack::make_stack).clone()
+    }
+    // Look up a register the controller refers to, allocating it first with auto_registers.
+    fn assembly_register(&mut self, name: &str) -> Result<&Register, AssembleError> {
+        if self.options.auto_registers && !self.register_table.contains_key(name) {
+            self.allocate_register(name);
+        }
+        let reg = self.register_table.get(name).ok_or(AssembleError::UnknownRegister(name.to_string()))?;
+        if !matches!(name, "pc" | "flag") {
+            self.referenced_registers.insert(name.to_string());
+        }
+        Ok(reg)
     }
     fn install_operations(&mut self, ops: Vec<(String, Operation)>) {
         for (op_name, op) in ops {
             self.the_operations.insert(op_name.to_string(), op);
         }
     }
-    pub fn get_register(&mut self, name: &str) -> Result<&Register, String> {
-        self.register_table.get(name).ok_or(format!("Unknown register: {name}"))
+    pub fn get_register(&mut self, name: &str) -> Result<&Register, AssembleError> {
+        self.register_table.get(name).ok_or(AssembleError::UnknownRegister(name.to_string()))
+    }
+    // The registers of the machine apart from pc and flag: the declared ones plus,
+    // with auto_registers, every one the controller refers to.
+    pub fn register_names(&self) -> BTreeSet<&str> {
+        self.register_table
+            .keys()
+            .map(String::as_str)
+            .filter(|name| !matches!(*name, "pc" | "flag"))
+            .collect()
+    }
+    // The registers apart from pc and flag that the controller refers to, whether
+    // declared or not, to check a declared list against.
+    pub fn referenced_registers(&self) -> BTreeSet<&str> {
+        self.referenced_registers.iter().map(String::as_str).collect()
+    }
+    // The instructions executed since the machine was made or reset_stats was called (ex 5.15).
+    pub fn stats(&self) -> MachineStats {
+        let mut stats = MachineStats::default();
+        for (inst, &count) in self.the_instruction_sequence.iter().zip(&self.executions) {
+            if count == 0 {
+                continue;
+            }
+            stats.instructions += count;
+            *stats.by_kind.entry(inst.text.kind().name()).or_default() += count;
+            *stats.by_label.entry(inst.label.clone()).or_default() += count;
+        }
+        stats
+    }
+    pub fn reset_stats(&mut self) {
+        self.executions.fill(0);
     }
-    pub fn get_operation(&self, name: &str) -> Result<Operation, String> {
-        self.the_operations.get(name).cloned().ok_or(format!("Unknown operation: {name}"))    
+    // Report every instruction before it is executed (ex 5.16), to stderr unless
+    // another sink was set.
+    pub fn trace_on(&mut self) {
+        self.tracing = true;
+    }
+    pub fn trace_off(&mut self) {
+        self.tracing = false;
+    }
+    pub fn set_trace_sink(&mut self, sink: Box<dyn TraceSink>) {
+        self.trace_sink = Some(sink);
+    }
+    pub fn set_input(&mut self, input: Box<dyn BufRead>) {
+        self.input = Some(input);
+    }
+    pub fn input(&mut self) -> &mut dyn BufRead {
+        self.input.get_or_insert_with(|| Box::new(BufReader::new(io::stdin()))).as_mut()
+    }
+    pub fn set_output(&mut self, output: Box<dyn Write>) {
+        self.output = Some(output);
+    }
+    pub fn output(&mut self) -> &mut dyn Write {
+        self.output.get_or_insert_with(|| Box::new(io::stdout())).as_mut()
+    }
+    // Report every write to the register with its old and new value (ex 5.18).
+    pub fn trace_register_on(&mut self, name: &str) -> Result<(), RuntimeErrorKind> {
+        self.lookup_register(name)?.traced.set(true);
+        Ok(())
+    }
+    pub fn trace_register_off(&mut self, name: &str) -> Result<(), RuntimeErrorKind> {
+        self.lookup_register(name)?.traced.set(false);
+        Ok(())
+    }
+    // The data paths the controller uses, found from the assembled instructions (ex 5.12).
+    pub fn data_paths(&self) -> DataPaths {
+        analysis::analyze(self.the_instruction_sequence.iter().map(|inst| &inst.text))
+    }
+    pub fn get_operation(&self, name: &str) -> Result<Operation, AssembleError> {
+        self.the_operations.get(name).cloned().ok_or(AssembleError::UnknownOperation(name.to_string()))
     }
     pub fn stack(&mut self) -> &mut Stack {
         &mut self.stack
     }
+    // The statistics of the shared stack. With per_register_stacks nothing is saved
+    // there, so they stay at zero; see register_stack_stats instead.
+    pub fn stack_stats(&self) -> StackStats {
+        self.stack.statistics()
+    }
+    // The stack of every register by name with per_register_stacks, pc and flag only
+    // when the controller saves or restores them; empty otherwise, as all registers
+    // share the one stack.
+    pub fn register_stacks(&self) -> &BTreeMap<String, Stack> {
+        &self.register_stacks
+    }
+    pub fn register_stack_stats(&self) -> BTreeMap<&str, StackStats> {
+        self.register_stacks.iter().map(|(name, stack)| (name.as_str(), stack.statistics())).collect()
+    }
+    // Empty every stack of the machine and start counting afresh.
+    pub fn initialize_stacks(&mut self) {
+        self.stack.initialize();
+        for stack in self.register_stacks.values() {
+            stack.initialize();
+        }
+    }
     pub fn operations(&mut self) -> &mut HashMap<String, Operation> {
         &mut self.the_operations
     }
-    
-    pub fn start(&self) {}
+    pub fn set_register_contents(&mut self, name: &str, value: impl Into<Value>) -> Result<(), RuntimeErrorKind> {
+        let reg = self.lookup_register(name)?.clone();
+        self.write_register(&reg, value.into())
+    }
+    pub fn get_register_contents(&self, name: &str) -> Result<Value, RuntimeErrorKind> {
+        self.register_table
+            .get(name)
+            .ok_or(RuntimeErrorKind::UnknownRegister(name.to_string()))?
+            .read()
+    }
+    // What assembling the controller found suspicious, e.g. labels never referenced.
+    pub fn warnings(&self) -> &[AssembleWarning] {
+        &self.warnings
+    }
+    // Stop every run after this many executed instructions, None means no limit.
+    pub fn set_step_limit(&mut self, limit: Option<usize>) {
+        self.step_limit = limit;
+    }
+
+    pub fn start(&mut self) -> Result<Halted, RuntimeError> {
+        self.set_pc(0);
+        self.stopped_at = None;
+        self.execute(self.step_limit)
+    }
+    pub fn pc(&self) -> usize {
+        self.get_pc()
+    }
+    // The instruction at pc, or None once the machine has run off the end.
+    pub fn location(&self) -> Option<Location> {
+        let pc = self.get_pc();
+        let inst = self.the_instruction_sequence.get(pc)?;
+        let start = inst.label.as_ref().map_or(0, |label| self.label_table[label]);
+        Some(Location { pc, label: inst.label.clone(), offset: pc - start + 1, instruction: inst.text.to_string() })
+    }
+    // Execute exactly the one instruction at pc, ignoring breakpoints.
+    pub fn step(&mut self) -> Result<Step, RuntimeError> {
+        let pc = self.get_pc();
+        if pc >= self.the_instruction_sequence.len() {
+            return Ok(Step::Halted);
+        }
+        self.stopped_at = None;
+        let next = self.run_instruction(pc)?;
+        // Landing on a breakpoint counts as stopping there, so proceeding goes past it.
+        let landed = self.get_pc();
+        self.stopped_at = self.breakpoints.contains(&landed).then_some(landed);
+        match next {
+            Next::Advance => Ok(Step::Advanced { pc }),
+            Next::Jump(target) => Ok(Step::Jumped { pc, target }),
+        }
+    }
+    // Like proceed, but for at most `fuel` instructions.
+    pub fn run_for(&mut self, fuel: usize) -> Result<Halted, RuntimeError> {
+        self.execute(Some(fuel))
+    }
+    // Stop before the instruction `offset` instructions into the label, counting
+    // the one right after the label as 1, like (set-breakpoint machine 'label n) of ex 5.19.
+    pub fn set_breakpoint(&mut self, label: &str, offset: usize) -> Result<(), RuntimeErrorKind> {
+        let pc = self.breakpoint_pc(label, offset)?;
+        self.breakpoints.insert(pc);
+        Ok(())
+    }
+    pub fn cancel_breakpoint(&mut self, label: &str, offset: usize) -> Result<(), RuntimeErrorKind> {
+        let pc = self.breakpoint_pc(label, offset)?;
+        self.breakpoints.remove(&pc);
+        Ok(())
+    }
+    pub fn cancel_all_breakpoints(&mut self) {
+        self.breakpoints.clear();
+    }
+    // Continue from the current pc, past the breakpoint the machine stopped at if any.
+    pub fn proceed(&mut self) -> Result<Halted, RuntimeError> {
+        self.execute(self.step_limit)
+    }
+    fn breakpoint_pc(&self, label: &str, offset: usize) -> Result<usize, RuntimeErrorKind> {
+        let start = *self.label_table.get(label).ok_or_else(|| RuntimeErrorKind::UnknownLabel(label.to_string()))?;
+        let pc = start.checked_add(offset).and_then(|end| end.checked_sub(1)).filter(|_| offset > 0);
+        pc.filter(|pc| *pc < self.the_instruction_sequence.len())
+            .ok_or_else(|| RuntimeErrorKind::InvalidBreakpoint { label: label.to_string(), offset })
+    }
 }
 
 impl Machine {
-    fn advance_pc(&mut self) {
-        self.register_table.entry("pc".to_string())
-            .and_modify(|pc| {
-                pc.contents.borrow_mut().map(|val| val + 1);
-            });
+    // The fetch-execute loop: run the instruction at pc until pc falls off the end,
+    // a breakpoint is hit or the fuel runs out.
+    fn execute(&mut self, fuel: Option<usize>) -> Result<Halted, RuntimeError> {
+        let mut steps = 0;
+        let resumed_from = self.stopped_at.take();
+        loop {
+            let pc = self.get_pc();
+            if pc >= self.the_instruction_sequence.len() {
+                return Ok(Halted::Done);
+            }
+            if self.breakpoints.contains(&pc) && !(steps == 0 && resumed_from == Some(pc)) {
+                self.stopped_at = Some(pc);
+                return Ok(Halted::Breakpoint(pc));
+            }
+            if fuel.is_some_and(|limit| steps >= limit) {
+                return Ok(Halted::StepLimit(steps));
+            }
+            self.run_instruction(pc)?;
+            steps += 1;
+        }
+    }
+    // Execute the instruction at pc, which must exist, and move the pc on.
+    fn run_instruction(&mut self, pc: usize) -> Result<Next, RuntimeError> {
+        let proc = self.the_instruction_sequence[pc].proc.clone();
+        self.executions[pc] += 1;
+        if self.tracing {
+            let inst = &self.the_instruction_sequence[pc];
+            let event = TraceEvent::Instruction { pc, label: inst.label.as_deref(), instruction: &inst.text };
+            self.trace_sink.get_or_insert_with(|| Box::new(WriteSink::stderr())).trace(&event);
+        }
+        let next = proc(self).map_err(|kind| RuntimeError {
+            pc,
+            instruction: self.the_instruction_sequence[pc].text.to_string(),
+            span: self.the_instruction_sequence[pc].text.span(),
+            kind,
+        })?;
+        match next {
+            Next::Advance => self.set_pc(self.get_pc() + 1),
+            Next::Jump(target_pc) => self.set_pc(target_pc),
+        }
+        Ok(next)
+    }
+    // The pc holds the instruction pointer as a label value, or nothing before the
+    // first run: write_register refuses anything else.
+    fn get_pc(&self) -> usize {
+        match self.register_table.get("pc").and_then(Register::get_content) {
+            Some(Value::Label(pc)) => pc,
+            _ => 0,
+        }
+    }
+    fn set_pc(&mut self, new_pc: usize) {
+        if let Some(pc) = self.register_table.get("pc").cloned() {
+            self.store_register(&pc, Value::Label(new_pc));
+        }
+    }
+    fn set_flag(&mut self, new_flag: Value) {
+        if let Some(flag) = self.register_table.get("flag").cloned() {
+            self.store_register(&flag, new_flag);
+        }
+    }
+    // Every write from the controller or the accessors goes through here, so a value
+    // the register can't hold is refused before anything changes.
+    fn write_register(&mut self, reg: &Register, value: Value) -> Result<(), RuntimeErrorKind> {
+        reg.check(&value)?;
+        self.store_register(reg, value);
+        Ok(())
+    }
+    // Every write to a register, the pc included, ends here, so traced registers
+    // report it wherever it comes from.
+    fn store_register(&mut self, reg: &Register, value: Value) {
+        if reg.traced.get() {
+            let old = reg.get_content();
+            let event = TraceEvent::Register { pc: self.get_pc(), name: reg.name(), old: old.as_ref(), new: &value };
+            self.emit_trace(&event);
+        }
+        reg.set_content(value);
     }
-    fn set_pc(&mut self, new_pc: u32) {
-        self.register_table.entry("pc".to_string())
-            .and_modify(|pc| pc.set_content(new_pc));
+    fn emit_trace(&mut self, event: &TraceEvent) {
+        self.trace_sink.get_or_insert_with(|| Box::new(WriteSink::stderr())).trace(event);
     }
-    fn set_flag(&mut self, new_flag: Bool) {
-        self.register_table.entry("flag".to_string())
-            .and_modify(|flag| flag.set_content(new_flag));
+    fn lookup_register(&self, name: &str) -> Result<&Register, RuntimeErrorKind> {
+        self.register_table.get(name).ok_or(RuntimeErrorKind::UnknownRegister(name.to_string()))
+    }
+}
+
+// Like a register, a stack is a handle shared with the procedures that save and restore.
+#[derive(Debug, Clone)]
+pub struct Stack(Rc<RefCell<StackData>>);
+
+// The values together with the counters of SICP 5.2.4 for monitoring the stack.
+#[derive(Debug, Default)]
+struct StackData {
+    values: Vec<Value>,
+    // The register each value was saved from, None for values pushed directly.
+    saved_from: Vec<Option<Rc<str>>>,
+    number_pushes: usize,
+    max_depth: usize,
+}
+
+// What the stack has been through since it was last initialized.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct StackStats {
+    pub total_pushes: usize,
+    pub max_depth: usize,
+    pub current_depth: usize,
+}
+impl fmt::Display for StackStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(total-pushes = {} maximum-depth = {})", self.total_pushes, self.max_depth)
+    }
+}
+
+// Executed instructions, in total and broken down. Only what was executed shows up
+// in the breakdowns; instructions before the first label are counted under None.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct MachineStats {
+    pub instructions: usize,
+    pub by_kind: BTreeMap<&'static str, usize>,
+    pub by_label: BTreeMap<Option<String>, usize>,
+}
+impl fmt::Display for MachineStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(instruction-count = {})", self.instructions)
     }
 }
 
-#[derive(Debug)]
-struct Stack(Rc<RefCell<Vec<u32>>>);
 impl Default for Stack {
     fn default() -> Self {
         Stack::make_stack()
@@ -284,15 +812,718 @@ impl Default for Stack {
 }
 impl Stack {
     fn make_stack() -> Self {
-        Stack(Rc::new(RefCell::new(Vec::new())))
+        Stack(Rc::new(RefCell::new(StackData::default())))
+    }
+    pub fn push(&self, value: Value) {
+        let mut stack = self.0.borrow_mut();
+        stack.values.push(value);
+        stack.saved_from.push(None);
+        stack.number_pushes += 1;
+        stack.max_depth = stack.max_depth.max(stack.values.len());
+    }
+    pub fn pop(&self) -> Option<Value> {
+        let mut stack = self.0.borrow_mut();
+        stack.saved_from.pop();
+        stack.values.pop()
+    }
+    fn save(&self, reg: &Register, value: Value) {
+        self.push(value);
+        *self.0.borrow_mut().saved_from.last_mut().unwrap() = Some(reg.name.clone());
+    }
+    // Pop the value to restore into reg. When checked, a value saved from another
+    // register is an error and stays on the stack, as does one reg can't hold.
+    fn restore(&self, reg: &Register, checked: bool) -> Result<Value, RuntimeErrorKind> {
+        if checked && let Some(Some(saved)) = self.0.borrow().saved_from.last() && *saved != reg.name {
+            return Err(RuntimeErrorKind::RestoreMismatch { saved: saved.to_string(), restored: reg.name().to_string() });
+        }
+        if let Some(top) = self.0.borrow().values.last() {
+            reg.check(top)?;
+        }
+        self.pop().ok_or(RuntimeErrorKind::EmptyStack)
+    }
+    // Empty the stack and start counting afresh.
+    pub fn initialize(&self) {
+        *self.0.borrow_mut() = StackData::default();
+    }
+    // The values from the bottom of the stack to the top.
+    pub fn contents(&self) -> Vec<Value> {
+        self.0.borrow().values.clone()
+    }
+    pub fn statistics(&self) -> StackStats {
+        let stack = self.0.borrow();
+        StackStats {
+            total_pushes: stack.number_pushes,
+            max_depth: stack.max_depth,
+            current_depth: stack.values.len(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Arity, AssembleError, Executor, Halted, Machine, MachineError, Operation, op};
+    use super::{AssembleWarning, MachineOptions, MachineStats, OperandKind, Signature, StackStats, Step};
+    use super::operations::{standard_operations, OperationRegistry};
+    use super::{RuntimeError, RuntimeErrorKind, Span, Value, ValueKind};
+    use super::fixtures::{GCD, RECURSIVE_FACTORIAL};
+
+    #[derive(Clone)]
+    struct BinOp(fn(i64, i64) -> Value);
+    impl Executor for BinOp {
+        type Oprands = Vec<Value>;
+
+        fn execute(&self, _machine: &mut Machine, oprands: Vec<Value>) -> Result<Vec<Value>, RuntimeErrorKind> {
+            Ok(vec![(self.0)(oprands[0].as_integer()?, oprands[1].as_integer()?)])
+        }
+    }
+
+    fn arithmetic_ops() -> Vec<(String, Operation)> {
+        vec![
+            ("=".to_string(), Box::new(BinOp(|a, b| Value::Boolean(a == b))) as Operation),
+            ("<".to_string(), Box::new(BinOp(|a, b| Value::Boolean(a < b)))),
+            ("+".to_string(), Box::new(BinOp(|a, b| Value::Integer(a + b)))),
+            ("-".to_string(), Box::new(BinOp(|a, b| Value::Integer(a - b)))),
+            ("*".to_string(), Box::new(BinOp(|a, b| Value::Integer(a * b)))),
+        ]
+    }
+
+    #[test]
+    fn test_assemble_stack_and_jump_instructions() {
+        let controller = "
+          start
+            (assign continue (label done))
+            (save continue)
+            (restore n)
+            (perform (op +) (const 1) (const 2))
+            (goto (reg continue))
+            (goto (label start))
+          done";
+
+        let machine = Machine::make_machine(&["n", "continue"], arithmetic_ops(), controller);
+        assert_eq!(machine.map(|m| m.the_instruction_sequence.len()).ok(), Some(6));
+    }
+
+    #[test]
+    fn test_assemble_rejects_unknown_register_in_save() {
+        let machine = Machine::make_machine(&["n"], arithmetic_ops(), "(save m)");
+        assert_eq!(machine.err(), Some(MachineError::Assemble(AssembleError::UnknownRegister("m".to_string()))));
     }
-    fn push(&self, value: u32) {
-        self.0.borrow_mut().push(value);
+
+    #[test]
+    fn test_start_runs_until_falling_off_the_end() {
+        let controller = "
+            (save continue)
+            (restore a)
+            (goto (reg a))
+            (restore a)
+          done";
+
+        let mut machine = Machine::make_machine(&["a", "continue"], arithmetic_ops(), controller).unwrap();
+        machine.set_register_contents("continue", Value::Label(4)).unwrap();
+        assert_eq!(machine.start(), Ok(Halted::Done));
+        assert_eq!(machine.get_register_contents("a"), Ok(Value::Label(4)));
+        assert_eq!(machine.get_register_contents("pc"), Ok(Value::Label(4)));
     }
-    fn pop(&self) -> Option<u32> {
-        self.0.borrow_mut().pop()
+
+    #[test]
+    fn test_start_stops_at_step_limit() {
+        let controller = "
+          forever
+            (goto (label forever))";
+
+        let mut machine = Machine::make_machine(&[], arithmetic_ops(), controller).unwrap();
+        machine.set_step_limit(Some(100));
+        assert_eq!(machine.start(), Ok(Halted::StepLimit(100)));
     }
-    fn initialize(&self) {
-        self.0.borrow_mut().clear();
+
+    #[test]
+    fn test_start_reports_runtime_error_with_pc() {
+        let controller = "
+            (goto (label restore-it))
+          restore-it
+            (restore a)";
+
+        let mut machine = Machine::make_machine(&["a"], arithmetic_ops(), controller).unwrap();
+        let error = machine.start().unwrap_err();
+        let start = controller.find("(restore a)").unwrap();
+        assert_eq!(error, RuntimeError {
+            pc: 1,
+            instruction: "(restore a)".to_string(),
+            span: Span::locate(controller, start, start + 11),
+            kind: RuntimeErrorKind::EmptyStack,
+        });
+        assert_eq!(error.to_string(), "Runtime error at pc 1 (restore a): Empty stack");
+        assert_eq!(error.render(controller), "\
+error: Runtime error at pc 1: Empty stack
+ --> 4:13
+  |
+4 |             (restore a)
+  |             ^^^^^^^^^^^
+");
+        assert_eq!(machine.get_register_contents("a"), Err(RuntimeErrorKind::UnassignedRegister("a".to_string())));
+    }
+
+    #[test]
+    fn test_recursive_factorial() {
+        let controller = RECURSIVE_FACTORIAL;
+
+        let mut machine = Machine::make_machine(&["n", "val", "continue"], arithmetic_ops(), controller).unwrap();
+        machine.set_register_contents("n", 5).unwrap();
+        assert_eq!(machine.start(), Ok(Halted::Done));
+        assert_eq!(machine.get_register_contents("val"), Ok(Value::Integer(120)));
+    }
+
+    #[test]
+    fn test_register_operands_are_read_when_executed() {
+        let controller = "
+            (assign b (reg a))
+            (assign a (const 7))
+            (assign c (reg a))";
+
+        let mut machine = Machine::make_machine(&["a", "b", "c"], arithmetic_ops(), controller).unwrap();
+        machine.set_register_contents("a", 3).unwrap();
+        assert_eq!(machine.start(), Ok(Halted::Done));
+        assert_eq!(machine.get_register_contents("b"), Ok(Value::Integer(3)));
+        assert_eq!(machine.get_register_contents("c"), Ok(Value::Integer(7)));
+    }
+
+    #[test]
+    fn test_reading_unassigned_register_is_a_runtime_error() {
+        let mut machine = Machine::make_machine(&["a", "b"], arithmetic_ops(), "(assign b (reg a))").unwrap();
+        assert_eq!(machine.start().map_err(|e| e.kind), Err(RuntimeErrorKind::UnassignedRegister("a".to_string())));
+    }
+
+    #[test]
+    fn test_make_machine_reports_errors_by_phase() {
+        let parse_error = Machine::make_machine(&["a"], arithmetic_ops(), "(assign a (const 1)) (jump)").err().unwrap();
+        assert!(matches!(parse_error, MachineError::Parse(ref e) if e.span.start == 22));
+
+        let unknown_label = Machine::make_machine(&["a"], arithmetic_ops(), "(goto (label nowhere))").err().unwrap();
+        assert!(matches!(unknown_label, MachineError::Assemble(AssembleError::UndefinedLabels(ref labels)) if labels[0].get_name() == "nowhere"));
+
+        let unknown_op = Machine::make_machine(&["a"], arithmetic_ops(), "(assign a (op rem) (reg a) (reg a))").err().unwrap();
+        assert_eq!(unknown_op.to_string(), "Assembling controller text error: Unknown operation: rem");
+    }
+
+    #[test]
+    fn test_registers_hold_values_of_every_kind() {
+        let controller = "
+            (assign a (const -3))
+            (assign b (const #t))
+            (assign c (const done))
+            (assign d (const \"hello\"))
+            (assign e (label here))
+          here";
+
+        let mut machine = Machine::make_machine(&["a", "b", "c", "d", "e"], arithmetic_ops(), controller).unwrap();
+        assert_eq!(machine.start(), Ok(Halted::Done));
+        let contents: Vec<String> = ["a", "b", "c", "d", "e"].iter()
+            .map(|name| machine.get_register_contents(name).unwrap().to_string())
+            .collect();
+        assert_eq!(contents, vec!["-3", "#t", "done", "\"hello\"", "#<label 5>"]);
+    }
+
+    #[test]
+    fn test_operation_on_wrong_kind_is_a_type_error() {
+        let mut machine = Machine::make_machine(&["a"], arithmetic_ops(), "(assign a (op +) (const foo) (const 1))").unwrap();
+        let error = machine.start().unwrap_err();
+        assert_eq!(error.kind.root_cause(), &RuntimeErrorKind::TypeMismatch {
+            expected: ValueKind::Integer,
+            found: Value::Symbol("foo".to_string()),
+        });
+        assert_eq!(error.to_string(), "Runtime error at pc 0 (assign a (op +) (const foo) (const 1)): Operation '+' failed on (foo 1): Expected a value of kind integer, but got the symbol foo");
+    }
+
+    #[test]
+    fn test_goto_register_requires_a_label() {
+        let mut machine = Machine::make_machine(&["a"], arithmetic_ops(), "(assign a (const 1)) (goto (reg a))").unwrap();
+        let error = machine.start().unwrap_err();
+        assert_eq!((error.pc, error.kind), (1, RuntimeErrorKind::TypeMismatch {
+            expected: ValueKind::Label,
+            found: Value::Integer(1),
+        }));
+    }
+
+    #[test]
+    fn test_branch_without_test_is_a_runtime_error() {
+        let mut machine = Machine::make_machine(&[], arithmetic_ops(), "(branch (label done)) done").unwrap();
+        let error = machine.start().unwrap_err();
+        assert_eq!((error.pc, error.kind), (0, RuntimeErrorKind::UnassignedRegister("flag".to_string())));
+    }
+
+    #[test]
+    fn test_pc_only_holds_labels() {
+        let mut machine = Machine::make_machine(&["a"], arithmetic_ops(), "(assign a (const 1)) (assign pc (const 2))").unwrap();
+        let mismatch = RuntimeErrorKind::TypeMismatch { expected: ValueKind::Label, found: Value::Integer(2) };
+        assert_eq!(machine.set_register_contents("pc", 2), Err(mismatch.clone()));
+        assert_eq!(machine.pc(), 0);
+
+        machine.set_register_contents("pc", Value::Label(1)).unwrap();
+        let error = machine.step().unwrap_err();
+        assert_eq!((error.pc, error.kind), (1, mismatch.clone()));
+        assert_eq!(machine.pc(), 1);
+
+        // A restore that would put a non-label in the pc leaves the value on the stack.
+        let mut machine = Machine::make_machine(&["a"], arithmetic_ops(), "(save a) (restore pc)").unwrap();
+        machine.set_register_contents("a", 2).unwrap();
+        let error = machine.start().unwrap_err();
+        assert_eq!((error.pc, error.kind), (1, mismatch));
+        assert_eq!((machine.pc(), machine.stack().contents()), (1, vec![Value::Integer(2)]));
+    }
+
+    fn rem(a: u32, b: u32) -> u32 {
+        a % b
+    }
+
+    #[test]
+    fn test_plain_functions_and_closures_as_operations() {
+        let controller = format!("(assign b (op forty)) {GCD}");
+        let ops = vec![
+            op("rem", rem),
+            op("=", |a: i64, b: i64| a == b),
+            op("forty", || 40i64),
+        ];
+
+        let mut machine = Machine::make_machine(&["a", "b", "t"], ops, &controller).unwrap();
+        machine.set_register_contents("a", 206).unwrap();
+        assert_eq!(machine.start(), Ok(Halted::Done));
+        assert_eq!(machine.get_register_contents("a"), Ok(Value::Integer(2)));
+    }
+
+    #[test]
+    fn test_fallible_function_as_operation() {
+        let div = |a: i64, b: i64| if b == 0 { Err(RuntimeErrorKind::DivisionByZero) } else { Ok(a / b) };
+        let mut machine = Machine::make_machine(&["a"], vec![op("div", div)], "(assign a (op div) (const 7) (const 2)) (assign a (op div) (reg a) (const 0))").unwrap();
+        let error = machine.start().unwrap_err();
+        assert_eq!((error.pc, error.kind.root_cause()), (1, &RuntimeErrorKind::DivisionByZero));
+        assert_eq!(machine.get_register_contents("a"), Ok(Value::Integer(3)));
+
+        let sqrt = |n: i64| if n < 0 { Err(format!("no square root of {n}")) } else { Ok(n.isqrt()) };
+        let mut machine = Machine::make_machine(&["a"], vec![op("sqrt", sqrt)], "(assign a (op sqrt) (const -4))").unwrap();
+        assert_eq!(machine.start().unwrap_err().kind.root_cause(), &RuntimeErrorKind::Custom("no square root of -4".to_string()));
+    }
+
+    #[test]
+    fn test_failed_operation_reports_its_operands() {
+        let controller = "
+            (assign b (const 0))
+            (save a)
+            (assign t (op rem) (reg a) (reg b))
+            (assign a (const 1))";
+        let mut machine = Machine::make_machine(&["a", "b", "t"], standard_operations(), controller).unwrap();
+        machine.set_register_contents("a", 206).unwrap();
+
+        let error = machine.start().unwrap_err();
+        assert_eq!(error.pc, 2);
+        assert_eq!(error.instruction, "(assign t (op rem) (reg a) (reg b))");
+        assert_eq!(error.kind, RuntimeErrorKind::OperationFailed {
+            operation: "rem".to_string(),
+            operands: vec![Value::Integer(206), Value::Integer(0)],
+            cause: Box::new(RuntimeErrorKind::DivisionByZero),
+        });
+        assert_eq!(error.to_string(), "Runtime error at pc 2 (assign t (op rem) (reg a) (reg b)): Operation 'rem' failed on (206 0): Division by zero");
+
+        // The machine is left as it was when the operation failed.
+        assert_eq!(machine.get_register_contents("pc"), Ok(Value::Label(2)));
+        assert_eq!(machine.get_register_contents("a"), Ok(Value::Integer(206)));
+        assert_eq!(machine.get_register_contents("t"), Err(RuntimeErrorKind::UnassignedRegister("t".to_string())));
+        assert_eq!(machine.stack().pop(), Some(Value::Integer(206)));
+    }
+
+    #[test]
+    fn test_arity_from_signature_is_checked_when_assembling() {
+        let error = Machine::make_machine(&["a"], vec![op("rem", rem)], "(assign a (op rem) (reg a))").err().unwrap();
+        assert_eq!(error, MachineError::Assemble(AssembleError::Arity {
+            operation: "rem".to_string(),
+            expected: Arity::Fixed(2),
+            found: 1,
+        }));
+
+        // An operand that doesn't fit the argument type is still a run-time error.
+        let mut machine = Machine::make_machine(&["a"], vec![op("rem", rem)], "(assign a (op rem) (const -1) (const 2))").unwrap();
+        assert_eq!(machine.start().unwrap_err().kind.root_cause(), &RuntimeErrorKind::Overflow);
+    }
+
+    #[test]
+    fn test_assemble_checks_operation_signatures() {
+        let assemble = |controller: &str| Machine::make_machine(&["a"], standard_operations(), controller).err();
+
+        assert_eq!(assemble("(assign a (op rem) (reg a))"), Some(MachineError::Assemble(AssembleError::Arity {
+            operation: "rem".to_string(),
+            expected: Arity::Fixed(2),
+            found: 1,
+        })));
+        assert_eq!(assemble("(assign a (op -))").unwrap().to_string(), "Assembling controller text error: Operation '-' expects at least 1 operands, but got 0");
+
+        // SICP exercise 5.9: operations can't be applied to labels.
+        let error = assemble("here (assign a (op +) (const 1) (label here))").unwrap();
+        assert_eq!(error, MachineError::Assemble(AssembleError::OperandKind {
+            operation: "+".to_string(),
+            position: 2,
+            expected: OperandKind::Kind(ValueKind::Integer),
+            found: ValueKind::Label,
+        }));
+        let error = assemble("here (test (op eq?) (reg a) (label here))").unwrap();
+        assert_eq!(error.to_string(), "Assembling controller text error: Operation 'eq?' cannot be applied to a label (operand 2)");
+
+        let error = assemble("(test (op =) (const done) (reg a))").unwrap();
+        assert_eq!(error.to_string(), "Assembling controller text error: Operand 1 of operation '=' should be of kind integer, but got symbol");
+        assert!(assemble("(test (op eq?) (const done) (reg a)) (perform (op print) (const 1) (const \"a\"))").is_none());
+    }
+
+    #[derive(Clone)]
+    struct LabelOffset;
+    impl Executor for LabelOffset {
+        type Oprands = Vec<Value>;
+
+        fn execute(&self, _machine: &mut Machine, oprands: Vec<Value>) -> Result<Vec<Value>, RuntimeErrorKind> {
+            Ok(vec![Value::Label(oprands[0].as_label()? + oprands.get(1).map_or(Ok(0), Value::as_integer)? as usize)])
+        }
+        fn signature(&self) -> Signature {
+            Signature::new(Arity::Range(1, 2)).with_operands(&[
+                OperandKind::Kind(ValueKind::Label),
+                OperandKind::Kind(ValueKind::Integer),
+            ])
+        }
+    }
+
+    #[test]
+    fn test_operations_can_declare_label_operands() {
+        let ops = vec![("offset".to_string(), Box::new(LabelOffset) as Operation)];
+        let controller = "(assign a (op offset) (label here) (const 1)) here (goto (reg a)) (assign a (const 7))";
+        let mut machine = Machine::make_machine(&["a"], ops.clone(), controller).unwrap();
+        assert_eq!(machine.start(), Ok(Halted::Done));
+        assert_eq!(machine.get_register_contents("a"), Ok(Value::Integer(7)));
+
+        let error = Machine::make_machine(&["a"], ops, "here (assign a (op offset) (label here) (const 1) (const 2))").err().unwrap();
+        assert_eq!(error.to_string(), "Assembling controller text error: Operation 'offset' expects 1 to 2 operands, but got 3");
+    }
+
+    #[test]
+    fn test_duplicate_label_is_an_error() {
+        // The controller of SICP exercise 5.8.
+        let controller = "
+          start
+            (goto (label here))
+          here
+            (assign a (const 3))
+            (goto (label there))
+          here
+            (assign a (const 4))
+            (goto (label there))
+          there";
+
+        let error = Machine::make_machine(&["a"], arithmetic_ops(), controller).err().unwrap();
+        let MachineError::Assemble(error) = error else { panic!("expected an assemble error") };
+        assert!(matches!(error, AssembleError::DuplicateLabel { ref name, first, second }
+            if name == "here" && (first.line, second.line) == (4, 7)));
+        assert_eq!(error.to_string(), "Duplicate label: here at 4:11 and 7:11");
+        assert_eq!(error.render(controller), "\
+error: Duplicate label: here, first defined at 4:11
+ --> 7:11
+  |
+7 |           here
+  |           ^^^^
+");
+    }
+
+    #[test]
+    fn test_every_undefined_label_is_reported() {
+        let controller = "(goto (label nowhere))\n(branch (label elsewhere))\n(assign a (label done))\ndone";
+        let error = Machine::make_machine(&["a"], arithmetic_ops(), controller).err().unwrap();
+        let MachineError::Assemble(error) = error else { panic!("expected an assemble error") };
+        assert_eq!(error.to_string(), "Undefined labels: nowhere at 1:14, elsewhere at 2:16");
+        assert_eq!(error.render(controller).matches("error: Undefined label").count(), 2);
+    }
+
+    #[test]
+    fn test_unreferenced_labels_are_warnings() {
+        let controller = "start (assign a (label done)) loop (goto (reg a)) done";
+        let machine = Machine::make_machine(&["a"], arithmetic_ops(), controller).unwrap();
+        let warnings: Vec<String> = machine.warnings().iter().map(AssembleWarning::to_string).collect();
+        assert_eq!(warnings, vec![
+            "Label start at 1:1 is never referenced",
+            "Label loop at 1:31 is never referenced",
+        ]);
+    }
+
+    #[test]
+    fn test_auto_registers_are_allocated_on_first_reference() {
+        let controller = GCD;
+        let options = MachineOptions { auto_registers: true, ..MachineOptions::default() };
+
+        let mut machine = Machine::make_machine_with_options(&[], standard_operations(), controller, options).unwrap();
+        assert_eq!(machine.register_names().into_iter().collect::<Vec<_>>(), vec!["a", "b", "t"]);
+        machine.set_register_contents("a", 206).unwrap();
+        machine.set_register_contents("b", 40).unwrap();
+        assert_eq!(machine.start(), Ok(Halted::Done));
+        assert_eq!(machine.get_register_contents("a"), Ok(Value::Integer(2)));
+
+        assert_eq!(machine.referenced_registers(), machine.register_names());
+
+        // Declared registers are kept even when unused; only the referenced ones show which.
+        let machine = Machine::make_machine_with_options(&["a", "b", "n"], standard_operations(), controller, options).unwrap();
+        assert_eq!(machine.register_names().into_iter().collect::<Vec<_>>(), vec!["a", "b", "n", "t"]);
+        assert_eq!(machine.referenced_registers().into_iter().collect::<Vec<_>>(), vec!["a", "b", "t"]);
+        let unused: Vec<&str> = machine.register_names().difference(&machine.referenced_registers()).copied().collect();
+        assert_eq!(unused, vec!["n"]);
+
+        let error = Machine::make_machine(&["a", "b"], standard_operations(), controller).err().unwrap();
+        assert_eq!(error, MachineError::Assemble(AssembleError::UnknownRegister("t".to_string())));
+    }
+
+    #[test]
+    fn test_stack_statistics_of_recursive_factorial() {
+        // As in SICP 5.2.4, computing n! takes 2n - 2 pushes and a maximum depth of 2n - 2.
+        for n in [1, 5, 10] {
+            let mut machine = Machine::make_machine(&["n", "val", "continue"], arithmetic_ops(), RECURSIVE_FACTORIAL).unwrap();
+            machine.set_register_contents("n", n).unwrap();
+            assert_eq!(machine.start(), Ok(Halted::Done));
+            let expected = 2 * n as usize - 2;
+            assert_eq!(machine.stack_stats(), StackStats { total_pushes: expected, max_depth: expected, current_depth: 0 });
+        }
+        assert_eq!(StackStats { total_pushes: 8, max_depth: 8, current_depth: 0 }.to_string(), "(total-pushes = 8 maximum-depth = 8)");
+    }
+
+    #[test]
+    fn test_stack_operations_are_available_to_controllers() {
+        let controller = "
+            (save a)
+            (save a)
+            (restore a)
+            (perform (op print-stack-statistics))
+            (perform (op initialize-stack))
+            (save a)";
+        let mut machine = Machine::make_machine(&["a"], arithmetic_ops(), controller).unwrap();
+        machine.set_register_contents("a", 1).unwrap();
+        assert_eq!(machine.start(), Ok(Halted::Done));
+        assert_eq!(machine.stack_stats(), StackStats { total_pushes: 1, max_depth: 1, current_depth: 1 });
+
+        let error = Machine::make_machine(&["a"], arithmetic_ops(), "(perform (op initialize-stack) (reg a))").err().unwrap();
+        assert!(matches!(error, MachineError::Assemble(AssembleError::Arity { .. })));
+    }
+
+    #[test]
+    fn test_instruction_counts_by_kind_and_label() {
+        let mut machine = Machine::make_machine(&["n", "val", "continue"], arithmetic_ops(), RECURSIVE_FACTORIAL).unwrap();
+        machine.set_register_contents("n", 3).unwrap();
+        assert_eq!(machine.start(), Ok(Halted::Done));
+
+        let stats = machine.stats();
+        assert_eq!(stats.instructions, 27);
+        assert_eq!(stats.to_string(), "(instruction-count = 27)");
+        assert_eq!(stats.by_kind["goto"], 5);
+        assert_eq!(stats.by_kind["save"], 4);
+        assert!(!stats.by_kind.contains_key("perform"));
+        let by_label: Vec<(Option<&str>, usize)> = stats.by_label.iter().map(|(label, count)| (label.as_deref(), *count)).collect();
+        assert_eq!(by_label, vec![(None, 1), (Some("after-fact"), 8), (Some("base-case"), 2), (Some("fact-loop"), 16)]);
+
+        machine.reset_stats();
+        assert_eq!(machine.stats(), MachineStats::default());
+        machine.set_register_contents("n", 1).unwrap();
+        assert_eq!(machine.start(), Ok(Halted::Done));
+        assert_eq!(machine.stats().instructions, 5);
+    }
+
+    #[test]
+    fn test_breakpoints_stop_before_the_instruction() {
+        let mut machine = Machine::make_machine(&["a", "b", "t"], standard_operations(), GCD).unwrap();
+        machine.set_register_contents("a", 206).unwrap();
+        machine.set_register_contents("b", 40).unwrap();
+        machine.set_breakpoint("test-b", 4).unwrap();
+
+        assert_eq!(machine.start(), Ok(Halted::Breakpoint(3)));
+        assert_eq!(machine.get_register_contents("a"), Ok(Value::Integer(206)));
+        assert_eq!(machine.get_register_contents("t"), Ok(Value::Integer(6)));
+
+        assert_eq!(machine.proceed(), Ok(Halted::Breakpoint(3)));
+        assert_eq!(machine.get_register_contents("a"), Ok(Value::Integer(40)));
+        assert_eq!(machine.get_register_contents("t"), Ok(Value::Integer(4)));
+
+        machine.cancel_breakpoint("test-b", 4).unwrap();
+        assert_eq!(machine.proceed(), Ok(Halted::Done));
+        assert_eq!(machine.get_register_contents("a"), Ok(Value::Integer(2)));
+        assert_eq!(machine.proceed(), Ok(Halted::Done));
+    }
+
+    #[test]
+    fn test_breakpoint_offsets_are_checked() {
+        let mut machine = Machine::make_machine(&["a", "b", "t"], standard_operations(), GCD).unwrap();
+        assert_eq!(machine.set_breakpoint("nowhere", 1), Err(RuntimeErrorKind::UnknownLabel("nowhere".to_string())));
+        assert_eq!(machine.set_breakpoint("test-b", 0), Err(RuntimeErrorKind::InvalidBreakpoint { label: "test-b".to_string(), offset: 0 }));
+        assert_eq!(machine.set_breakpoint("gcd-done", 1).unwrap_err().to_string(), "No instruction at offset 1 from label gcd-done");
+        // gcd-done starts at pc 6, so a huge offset must not overflow.
+        assert_eq!(machine.set_breakpoint("gcd-done", usize::MAX), Err(RuntimeErrorKind::InvalidBreakpoint { label: "gcd-done".to_string(), offset: usize::MAX }));
+
+        machine.set_breakpoint("test-b", 1).unwrap();
+        machine.set_breakpoint("test-b", 6).unwrap();
+        machine.set_register_contents("a", 206).unwrap();
+        machine.set_register_contents("b", 40).unwrap();
+        assert_eq!(machine.start(), Ok(Halted::Breakpoint(0)));
+        assert_eq!(machine.proceed(), Ok(Halted::Breakpoint(5)));
+        machine.cancel_all_breakpoints();
+        assert_eq!(machine.proceed(), Ok(Halted::Done));
+    }
+
+    #[test]
+    fn test_step_executes_one_instruction() {
+        let mut machine = Machine::make_machine(&["a", "b", "t"], standard_operations(), GCD).unwrap();
+        machine.set_register_contents("a", 6).unwrap();
+        machine.set_register_contents("b", 4).unwrap();
+        machine.set_register_contents("pc", Value::Label(0)).unwrap();
+        machine.set_breakpoint("test-b", 1).unwrap();
+
+        let steps: Vec<Step> = (0..6).map(|_| machine.step().unwrap()).collect();
+        assert_eq!(steps, vec![
+            Step::Advanced { pc: 0 },
+            Step::Advanced { pc: 1 },
+            Step::Advanced { pc: 2 },
+            Step::Advanced { pc: 3 },
+            Step::Advanced { pc: 4 },
+            Step::Jumped { pc: 5, target: 0 },
+        ]);
+        assert_eq!(machine.get_register_contents("t"), Ok(Value::Integer(2)));
+
+        machine.cancel_all_breakpoints();
+        assert_eq!(machine.run_for(100), Ok(Halted::Done));
+        assert_eq!(machine.step(), Ok(Step::Halted));
+
+        machine.set_register_contents("pc", Value::Label(0)).unwrap();
+        machine.set_register_contents("b", 0).unwrap();
+        machine.set_register_contents("a", Value::Symbol("x".to_string())).unwrap();
+        assert_eq!(machine.step(), Ok(Step::Advanced { pc: 0 }));
+        assert_eq!(machine.step(), Ok(Step::Jumped { pc: 1, target: 6 }));
+        machine.set_register_contents("pc", Value::Label(2)).unwrap();
+        assert_eq!(machine.step().unwrap_err().pc, 2);
+    }
+
+    #[test]
+    fn test_proceed_after_stepping_onto_a_breakpoint() {
+        let mut machine = Machine::make_machine(&["a", "b", "t"], standard_operations(), GCD).unwrap();
+        machine.set_register_contents("a", 206).unwrap();
+        machine.set_register_contents("b", 40).unwrap();
+        machine.set_breakpoint("test-b", 4).unwrap();
+        for _ in 0..3 {
+            machine.step().unwrap();
+        }
+        assert_eq!(machine.pc(), 3);
+        // Proceeding runs a whole loop before stopping at the breakpoint again.
+        assert_eq!(machine.proceed(), Ok(Halted::Breakpoint(3)));
+        assert_eq!(machine.get_register_contents("a"), Ok(Value::Integer(40)));
+        assert_eq!(machine.stats().instructions, 9);
+    }
+
+    #[test]
+    fn test_run_for_bounds_an_infinite_loop() {
+        let mut machine = Machine::make_machine(&["a"], standard_operations(), "loop (assign a (const 1)) (goto (label loop))").unwrap();
+        machine.set_register_contents("pc", Value::Label(0)).unwrap();
+        assert_eq!(machine.run_for(5), Ok(Halted::StepLimit(5)));
+        assert_eq!(machine.get_register_contents("pc"), Ok(Value::Label(1)));
+        assert_eq!(machine.run_for(1), Ok(Halted::StepLimit(1)));
+        assert_eq!(machine.stats().instructions, 6);
+    }
+
+    #[test]
+    fn test_load_machine_by_name_from_definitions() {
+        let source = format!("(define-machine gcd (registers a b t) (operations rem =) (controller {GCD}))");
+        let mut machine = Machine::load(&source, "gcd", &OperationRegistry::standard()).unwrap();
+        machine.set_register_contents("a", 206).unwrap();
+        machine.set_register_contents("b", 40).unwrap();
+        assert_eq!(machine.start(), Ok(Halted::Done));
+        assert_eq!(machine.get_register_contents("a"), Ok(Value::Integer(2)));
+        // Only the listed operations are bound, besides the machine's own.
+        assert!(machine.get_operation("rem").is_ok() && machine.get_operation("+").is_err());
+        assert!(machine.get_operation("initialize-stack").is_ok());
+
+        let mut registry = OperationRegistry::new();
+        registry.register("=", |a: i64, b: i64| a == b).register("rem", |a: i64, b: i64| a % b);
+        let mut machine = Machine::load(&source, "gcd", &registry).unwrap();
+        machine.set_register_contents("a", 12).unwrap();
+        machine.set_register_contents("b", 18).unwrap();
+        machine.start().unwrap();
+        assert_eq!(machine.get_register_contents("a"), Ok(Value::Integer(6)));
+
+        let error = |source: &str, name: &str| Machine::load(source, name, &registry).err().unwrap();
+        assert_eq!(error(&source, "fib"), MachineError::Assemble(AssembleError::UnknownMachine("fib".to_string())));
+        let unknown = source.replace("rem =", "rem = mod");
+        assert_eq!(error(&unknown, "gcd"), MachineError::Assemble(AssembleError::UnknownOperation("mod".to_string())));
+        let unlisted = source.replace("rem =", "rem");
+        assert_eq!(error(&unlisted, "gcd"), MachineError::Assemble(AssembleError::UnknownOperation("=".to_string())));
+        let undeclared = source.replace("a b t", "a b");
+        assert_eq!(error(&undeclared, "gcd"), MachineError::Assemble(AssembleError::UnknownRegister("t".to_string())));
+        assert!(matches!(error("(define-machine gcd)", "gcd"), MachineError::Parse(_)));
+    }
+
+    #[test]
+    fn test_per_register_stacks() {
+        // Restoring in the order of saving swaps x and y on the shared stack, but not on per-register stacks.
+        let controller = "
+            (save x)
+            (save y)
+            (restore x)
+            (restore y)
+            (perform (op initialize-stack))";
+        let run = |options: MachineOptions| {
+            let mut machine = Machine::make_machine_with_options(&["x", "y"], arithmetic_ops(), controller, options).unwrap();
+            machine.set_register_contents("x", 1).unwrap();
+            machine.set_register_contents("y", 2).unwrap();
+            machine.set_step_limit(Some(4));
+            assert_eq!(machine.start(), Ok(Halted::StepLimit(4)));
+            let contents = (machine.get_register_contents("x").unwrap(), machine.get_register_contents("y").unwrap());
+            (machine, contents)
+        };
+
+        let (machine, contents) = run(MachineOptions::default());
+        assert_eq!(contents, (Value::Integer(2), Value::Integer(1)));
+        assert!(machine.register_stacks().is_empty());
+        assert_eq!(machine.stack_stats().total_pushes, 2);
+
+        let (mut machine, contents) = run(MachineOptions { per_register_stacks: true, ..MachineOptions::default() });
+        assert_eq!(contents, (Value::Integer(1), Value::Integer(2)));
+        assert_eq!(machine.stack_stats().total_pushes, 0);
+        let stats = machine.register_stack_stats();
+        assert_eq!(stats.keys().copied().collect::<Vec<_>>(), vec!["x", "y"]);
+        assert_eq!(stats["x"], StackStats { total_pushes: 1, max_depth: 1, current_depth: 0 });
+
+        machine.register_stacks()["y"].push(Value::Integer(3));
+        assert_eq!(machine.run_for(1), Ok(Halted::Done));
+        assert!(machine.register_stack_stats().values().all(|stats| *stats == StackStats::default()));
+
+        // pc and flag only have a stack once the controller saves them.
+        let options = MachineOptions { per_register_stacks: true, ..MachineOptions::default() };
+        let machine = Machine::make_machine_with_options(&["x"], arithmetic_ops(), "(save flag)", options).unwrap();
+        assert_eq!(machine.register_stack_stats().keys().copied().collect::<Vec<_>>(), vec!["flag", "x"]);
+    }
+
+    #[test]
+    fn test_checked_restore_rejects_another_register() {
+        let controller = "
+            (save x)
+            (save y)
+            (restore y)
+            (restore y)";
+        let options = MachineOptions { checked_restore: true, ..MachineOptions::default() };
+        let mut machine = Machine::make_machine_with_options(&["x", "y"], arithmetic_ops(), controller, options).unwrap();
+        machine.set_register_contents("x", 1).unwrap();
+        machine.set_register_contents("y", 2).unwrap();
+        let error = machine.start().unwrap_err();
+        assert_eq!(error.kind, RuntimeErrorKind::RestoreMismatch { saved: "x".to_string(), restored: "y".to_string() });
+        assert_eq!(error.to_string(), "Runtime error at pc 3 (restore y): Cannot restore into y the value saved from x");
+        // The offending value is left on the stack.
+        assert_eq!(machine.stack().contents(), vec![Value::Integer(1)]);
+
+        // Values pushed from outside the controller belong to no register.
+        machine.stack().pop();
+        machine.stack().push(Value::Integer(3));
+        machine.set_register_contents("pc", Value::Label(3)).unwrap();
+        assert_eq!(machine.run_for(1), Ok(Halted::Done));
+        assert_eq!(machine.get_register_contents("y"), Ok(Value::Integer(3)));
+
+        // Unchecked, the same controller leaves x's value in y.
+        let mut machine = Machine::make_machine(&["x", "y"], arithmetic_ops(), controller).unwrap();
+        machine.set_register_contents("x", 1).unwrap();
+        machine.set_register_contents("y", 2).unwrap();
+        assert_eq!(machine.start(), Ok(Halted::Done));
+        assert_eq!(machine.get_register_contents("y"), Ok(Value::Integer(1)));
     }
 }
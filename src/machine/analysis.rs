@@ -0,0 +1,113 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt;
+
+use super::parser::{Instruction, InstructionKind, PrimitiveExpr};
+
+// The data paths a controller needs, as asked for by SICP exercise 5.12.
+// Everything is kept sorted and without duplicates.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DataPaths {
+    // The text of every distinct instruction, grouped by the kind of instruction.
+    pub instructions: BTreeMap<&'static str, BTreeSet<String>>,
+    // Registers used to hold the destination of a goto.
+    pub entry_registers: BTreeSet<String>,
+    // Registers that are saved or restored.
+    pub stack_registers: BTreeSet<String>,
+    // For each register, every source it is assigned from.
+    pub register_sources: BTreeMap<String, BTreeSet<String>>,
+}
+
+pub fn analyze<'a>(instructions: impl IntoIterator<Item = &'a Instruction>) -> DataPaths {
+    let mut paths = DataPaths::default();
+    for inst in instructions {
+        paths.instructions.entry(inst.kind().name()).or_default().insert(inst.to_string());
+        match inst.kind() {
+            InstructionKind::Assign { target_reg, val_expr } => {
+                paths.register_sources.entry(target_reg.clone()).or_default().insert(val_expr.to_string());
+            }
+            InstructionKind::Goto(PrimitiveExpr::Register(reg)) => {
+                paths.entry_registers.insert(reg.clone());
+            }
+            InstructionKind::Save { reg } | InstructionKind::Restore { reg } => {
+                paths.stack_registers.insert(reg.clone());
+            }
+            _ => {}
+        }
+    }
+    paths
+}
+
+impl fmt::Display for DataPaths {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let join = |names: &BTreeSet<String>| names.iter().cloned().collect::<Vec<String>>().join(" ");
+
+        writeln!(f, "Instructions:")?;
+        for (kind, texts) in &self.instructions {
+            writeln!(f, "  {kind}:")?;
+            for text in texts {
+                writeln!(f, "    {text}")?;
+            }
+        }
+        writeln!(f, "Entry point registers: {}", join(&self.entry_registers))?;
+        writeln!(f, "Saved or restored registers: {}", join(&self.stack_registers))?;
+        writeln!(f, "Register sources:")?;
+        for (reg, sources) in &self.register_sources {
+            writeln!(f, "  {reg}:")?;
+            for source in sources {
+                writeln!(f, "    {source}")?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::machine::fixtures::RECURSIVE_FACTORIAL;
+    use crate::machine::operations::standard_operations;
+    use crate::machine::Machine;
+
+    #[test]
+    fn test_data_paths_of_recursive_factorial() {
+        let machine = Machine::make_machine(&["n", "val", "continue"], standard_operations(), RECURSIVE_FACTORIAL).unwrap();
+        let paths = machine.data_paths();
+        assert_eq!(paths.instructions["goto"].len(), 2);
+        assert_eq!(paths.entry_registers.iter().collect::<Vec<_>>(), vec!["continue"]);
+        assert_eq!(paths.stack_registers.iter().collect::<Vec<_>>(), vec!["continue", "n"]);
+        assert_eq!(paths.register_sources["continue"].iter().collect::<Vec<_>>(), vec!["(label after-fact)", "(label fact-done)"]);
+
+        assert_eq!(paths.to_string(), "\
+Instructions:
+  assign:
+    (assign continue (label after-fact))
+    (assign continue (label fact-done))
+    (assign n (op -) (reg n) (const 1))
+    (assign val (const 1))
+    (assign val (op *) (reg n) (reg val))
+  branch:
+    (branch (label base-case))
+  goto:
+    (goto (label fact-loop))
+    (goto (reg continue))
+  restore:
+    (restore continue)
+    (restore n)
+  save:
+    (save continue)
+    (save n)
+  test:
+    (test (op =) (reg n) (const 1))
+Entry point registers: continue
+Saved or restored registers: continue n
+Register sources:
+  continue:
+    (label after-fact)
+    (label fact-done)
+  n:
+    (op -) (reg n) (const 1)
+  val:
+    (const 1)
+    (op *) (reg n) (reg val)
+");
+    }
+}
@@ -0,0 +1,55 @@
+use std::fmt;
+
+// A region of the controller text: byte offsets [start, end) plus the 1-based
+// line and column of `start`, so errors can point back at the source.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Span {
+    pub start: usize,
+    pub end: usize,
+    pub line: usize,
+    pub column: usize,
+}
+
+impl Span {
+    // Build a span by locating `start` in the source. Columns count chars, not bytes.
+    pub fn locate(source: &str, start: usize, end: usize) -> Self {
+        let before = &source[..start];
+        let line = before.matches('\n').count() + 1;
+        let line_start = before.rfind('\n').map_or(0, |i| i + 1);
+        let column = source[line_start..start].chars().count() + 1;
+        Span { start, end, line, column }
+    }
+}
+
+impl fmt::Display for Span {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.line, self.column)
+    }
+}
+
+// Render a rustc-style diagnostic: the message, the location, the offending
+// source line and a caret underline below the span.
+//
+// error: Expects a ')' at the end of the register expression
+//  --> 2:17
+//   |
+// 2 |   (assign a (reg b
+//   |                 ^
+pub fn render(source: &str, span: Span, message: &str) -> String {
+    let line_text = source.lines().nth(span.line - 1).unwrap_or("");
+    let gutter = " ".repeat(span.line.to_string().len());
+
+    // Underline the span, but never past the end of its first line.
+    let remaining_on_line = line_text.chars().count().saturating_sub(span.column - 1);
+    let width = source
+        .get(span.start..span.end)
+        .map_or(1, |text| text.chars().count())
+        .clamp(1, remaining_on_line.max(1));
+
+    format!(
+        "error: {message}\n{gutter}--> {span}\n{gutter} |\n{} | {line_text}\n{gutter} | {}{}\n",
+        span.line,
+        " ".repeat(span.column - 1),
+        "^".repeat(width),
+    )
+}
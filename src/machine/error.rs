@@ -0,0 +1,293 @@
+use std::error::Error;
+use std::fmt;
+
+use super::diagnostic::{self, Span};
+use super::parser::Label;
+use super::procedure::Arity;
+use super::value::{OperandKind, Value, ValueKind};
+
+// Every failure of the simulator, split by the phase it happens in:
+// parsing the controller text, assembling it into procedures, or running it.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum MachineError {
+    Parse(ParseError),
+    Assemble(AssembleError),
+    Runtime(RuntimeError),
+}
+
+impl MachineError {
+    // The error as a diagnostic, quoting the controller text where the location is known.
+    pub fn render(&self, source: &str) -> String {
+        match self {
+            MachineError::Parse(e) => e.render(source),
+            MachineError::Assemble(e) => e.render(source),
+            MachineError::Runtime(e) => e.render(source),
+        }
+    }
+}
+
+// `span` points at the token of the controller text where parsing went wrong.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ParseError {
+    pub span: Span,
+    pub message: String,
+}
+impl ParseError {
+    // The error as a diagnostic quoting the offending line of `source`.
+    pub fn render(&self, source: &str) -> String {
+        diagnostic::render(source, self.span, &self.message)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssembleError {
+    // Every reference to a label that the controller never defines.
+    UndefinedLabels(Vec<Label>),
+    // The same label defined twice (SICP exercise 5.8): where it was first and where again.
+    DuplicateLabel { name: String, first: Span, second: Span },
+    UnknownRegister(String),
+    UnknownOperation(String),
+    Arity { operation: String, expected: Arity, found: usize },
+    // A constant or label operand the operation doesn't take, `position` counts from 1.
+    OperandKind { operation: String, position: usize, expected: OperandKind, found: ValueKind },
+    InvalidDestination(String),
+    // No define-machine of this name in the source a machine was loaded from.
+    UnknownMachine(String),
+}
+
+impl AssembleError {
+    // The error as diagnostics quoting the controller text, when it is about labels
+    // with a known location. Other errors are rendered as a single line.
+    pub fn render(&self, source: &str) -> String {
+        match self {
+            AssembleError::UndefinedLabels(labels) => labels
+                .iter()
+                .map(|label| diagnostic::render(source, label.span(), &format!("Undefined label: {}", label.get_name())))
+                .collect(),
+            AssembleError::DuplicateLabel { name, first, second } => diagnostic::render(
+                source,
+                *second,
+                &format!("Duplicate label: {name}, first defined at {first}"),
+            ),
+            _ => format!("error: {self}\n"),
+        }
+    }
+}
+
+// Something suspicious about the controller that doesn't stop it from assembling.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AssembleWarning {
+    // A label that no instruction refers to.
+    UnusedLabel(Label),
+}
+
+// A failure while executing the instruction at `pc`, `instruction` is its controller text
+// and `span` where it was written.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct RuntimeError {
+    pub pc: usize,
+    pub instruction: String,
+    pub span: Span,
+    pub kind: RuntimeErrorKind,
+}
+
+impl RuntimeError {
+    // The error as a diagnostic pointing at the failing instruction.
+    pub fn render(&self, source: &str) -> String {
+        diagnostic::render(source, self.span, &format!("Runtime error at pc {}: {}", self.pc, self.kind))
+    }
+}
+
+// What went wrong at run time, without the location.
+// The register accessors of Machine also report these directly.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum RuntimeErrorKind {
+    UnknownRegister(String),
+    UnassignedRegister(String),
+    EmptyStack,
+    // With checked_restore, the top of the stack was saved from another register.
+    RestoreMismatch { saved: String, restored: String },
+    // An operation or instruction got a value of the wrong kind.
+    TypeMismatch { expected: ValueKind, found: Value },
+    // An operation used as a value returned nothing.
+    NoValue,
+    OperandCount { expected: usize, found: usize },
+    DivisionByZero,
+    Overflow,
+    // Input that `read` could not turn into a value, or failed I/O.
+    InvalidInput(String),
+    UnknownLabel(String),
+    // A breakpoint offset that doesn't point at an instruction.
+    InvalidBreakpoint { label: String, offset: usize },
+    // Any other failure reported by an operation, e.g. `Err("negative".to_string())`.
+    Custom(String),
+    // An operation failed, together with the operands it was applied to.
+    OperationFailed { operation: String, operands: Vec<Value>, cause: Box<RuntimeErrorKind> },
+}
+
+impl RuntimeErrorKind {
+    // What went wrong in the end, looking through OperationFailed.
+    pub fn root_cause(&self) -> &RuntimeErrorKind {
+        match self {
+            RuntimeErrorKind::OperationFailed { cause, .. } => cause.root_cause(),
+            _ => self,
+        }
+    }
+}
+
+impl fmt::Display for MachineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            MachineError::Parse(e) => write!(f, "Parsing controller text error: {e}"),
+            MachineError::Assemble(e) => write!(f, "Assembling controller text error: {e}"),
+            MachineError::Runtime(e) => write!(f, "{e}"),
+        }
+    }
+}
+
+impl fmt::Display for ParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} at {}", self.message, self.span)
+    }
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssembleError::UndefinedLabels(labels) => {
+                write!(f, "Undefined labels:")?;
+                for (i, label) in labels.iter().enumerate() {
+                    let separator = if i > 0 { "," } else { "" };
+                    write!(f, "{separator} {} at {}", label.get_name(), label.span())?;
+                }
+                Ok(())
+            }
+            AssembleError::DuplicateLabel { name, first, second } => {
+                write!(f, "Duplicate label: {name} at {first} and {second}")
+            }
+            AssembleError::UnknownRegister(name) => write!(f, "Unknown register: {name}"),
+            AssembleError::UnknownOperation(name) => write!(f, "Unknown operation: {name}"),
+            AssembleError::Arity { operation, expected, found } => write!(
+                f,
+                "Operation '{operation}' expects {expected} operands, but got {found}"
+            ),
+            AssembleError::OperandKind { operation, position, expected: OperandKind::Any, found } => write!(
+                f,
+                "Operation '{operation}' cannot be applied to a {found} (operand {position})"
+            ),
+            AssembleError::OperandKind { operation, position, expected, found } => write!(
+                f,
+                "Operand {position} of operation '{operation}' should be of kind {expected}, but got {found}"
+            ),
+            AssembleError::InvalidDestination(dest) => {
+                write!(f, "Goto expects a label or a register as destination, but got {dest}")
+            }
+            AssembleError::UnknownMachine(name) => write!(f, "Unknown machine: {name}"),
+        }
+    }
+}
+
+impl fmt::Display for AssembleWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AssembleWarning::UnusedLabel(label) => {
+                write!(f, "Label {} at {} is never referenced", label.get_name(), label.span())
+            }
+        }
+    }
+}
+
+impl fmt::Display for RuntimeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Runtime error at pc {} {}: {}", self.pc, self.instruction, self.kind)
+    }
+}
+
+impl fmt::Display for RuntimeErrorKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            RuntimeErrorKind::UnknownRegister(name) => write!(f, "Unknown register: {name}"),
+            RuntimeErrorKind::UnassignedRegister(name) => write!(f, "Unassigned register: {name}"),
+            RuntimeErrorKind::EmptyStack => write!(f, "Empty stack"),
+            RuntimeErrorKind::RestoreMismatch { saved, restored } => {
+                write!(f, "Cannot restore into {restored} the value saved from {saved}")
+            }
+            RuntimeErrorKind::TypeMismatch { expected, found } => {
+                write!(f, "Expected a value of kind {expected}, but got the {} {found}", found.kind())
+            }
+            RuntimeErrorKind::NoValue => write!(f, "The operation produced no value"),
+            RuntimeErrorKind::OperandCount { expected, found } => {
+                write!(f, "Expects {expected} operands, but got {found}")
+            }
+            RuntimeErrorKind::DivisionByZero => write!(f, "Division by zero"),
+            RuntimeErrorKind::Overflow => write!(f, "Integer overflow"),
+            RuntimeErrorKind::InvalidInput(message) => write!(f, "Invalid input: {message}"),
+            RuntimeErrorKind::UnknownLabel(name) => write!(f, "Unknown label: {name}"),
+            RuntimeErrorKind::InvalidBreakpoint { label, offset } => {
+                write!(f, "No instruction at offset {offset} from label {label}")
+            }
+            RuntimeErrorKind::Custom(message) => write!(f, "{message}"),
+            RuntimeErrorKind::OperationFailed { operation, operands, cause } => {
+                write!(f, "Operation '{operation}' failed on (")?;
+                for (i, operand) in operands.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{operand}")?;
+                }
+                write!(f, "): {cause}")
+            }
+        }
+    }
+}
+
+impl Error for MachineError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MachineError::Parse(e) => Some(e),
+            MachineError::Assemble(e) => Some(e),
+            MachineError::Runtime(e) => Some(e),
+        }
+    }
+}
+impl Error for ParseError {}
+impl Error for AssembleError {}
+impl Error for RuntimeError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        Some(&self.kind)
+    }
+}
+impl Error for RuntimeErrorKind {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            RuntimeErrorKind::OperationFailed { cause, .. } => Some(cause.as_ref()),
+            _ => None,
+        }
+    }
+}
+
+impl From<ParseError> for MachineError {
+    fn from(e: ParseError) -> Self {
+        MachineError::Parse(e)
+    }
+}
+impl From<AssembleError> for MachineError {
+    fn from(e: AssembleError) -> Self {
+        MachineError::Assemble(e)
+    }
+}
+impl From<RuntimeError> for MachineError {
+    fn from(e: RuntimeError) -> Self {
+        MachineError::Runtime(e)
+    }
+}
+impl From<String> for RuntimeErrorKind {
+    fn from(message: String) -> Self {
+        RuntimeErrorKind::Custom(message)
+    }
+}
+impl From<&str> for RuntimeErrorKind {
+    fn from(message: &str) -> Self {
+        RuntimeErrorKind::Custom(message.to_string())
+    }
+}
@@ -0,0 +1,33 @@
+// Controllers from SICP shared by the tests across the crate.
+
+// Figure 5.4 of SICP.
+pub const GCD: &str = "
+      test-b
+        (test (op =) (reg b) (const 0))
+        (branch (label gcd-done))
+        (assign t (op rem) (reg a) (reg b))
+        (assign a (reg b))
+        (assign b (reg t))
+        (goto (label test-b))
+      gcd-done";
+
+// Figure 5.11 of SICP.
+pub const RECURSIVE_FACTORIAL: &str = "
+        (assign continue (label fact-done))
+      fact-loop
+        (test (op =) (reg n) (const 1))
+        (branch (label base-case))
+        (save continue)
+        (save n)
+        (assign n (op -) (reg n) (const 1))
+        (assign continue (label after-fact))
+        (goto (label fact-loop))
+      after-fact
+        (restore n)
+        (restore continue)
+        (assign val (op *) (reg n) (reg val))
+        (goto (reg continue))
+      base-case
+        (assign val (const 1))
+        (goto (reg continue))
+      fact-done";
@@ -0,0 +1,427 @@
+use std::collections::HashMap;
+use std::io::BufRead;
+
+use super::error::{AssembleError, RuntimeErrorKind};
+use super::parser::parse_value;
+use super::procedure::{Arity, Executor, IntoOperation, Operation, Signature};
+use super::value::{OperandKind, Value, ValueKind};
+use super::Machine;
+
+type PrimitiveFn = fn(&[Value]) -> Result<Vec<Value>, RuntimeErrorKind>;
+
+// An operation of the standard library: a plain function over the operand values.
+#[derive(Clone)]
+struct Primitive {
+    f: PrimitiveFn,
+    signature: Signature,
+}
+
+impl Executor for Primitive {
+    type Oprands = Vec<Value>;
+
+    fn execute(&self, _machine: &mut Machine, oprands: Vec<Value>) -> Result<Vec<Value>, RuntimeErrorKind> {
+        (self.f)(&oprands)
+    }
+    fn signature(&self) -> Signature {
+        self.signature.clone()
+    }
+}
+
+type MachineFn = fn(&mut Machine, &[Value]) -> Result<Vec<Value>, RuntimeErrorKind>;
+
+// An operation on the machine itself rather than only on its operands.
+#[derive(Clone)]
+struct MachineOperation {
+    f: MachineFn,
+    signature: Signature,
+}
+
+impl Executor for MachineOperation {
+    type Oprands = Vec<Value>;
+
+    fn execute(&self, machine: &mut Machine, oprands: Vec<Value>) -> Result<Vec<Value>, RuntimeErrorKind> {
+        (self.f)(machine, &oprands)
+    }
+    fn signature(&self) -> Signature {
+        self.signature.clone()
+    }
+}
+
+const SYMBOL: &[OperandKind] = &[OperandKind::Kind(ValueKind::Symbol)];
+
+// Operations installed in every machine: initialize-stack and print-stack-statistics
+// from SICP 5.2.4, trace-on and trace-off from exercise 5.16, and
+// trace-register-on and trace-register-off taking a register name from exercise 5.18.
+const MACHINE_OPERATIONS: &[MachineEntry] = &[
+    ("initialize-stack", Arity::Fixed(0), ANY, |machine, _| {
+        machine.initialize_stacks();
+        Ok(Vec::new())
+    }),
+    ("print-stack-statistics", Arity::Fixed(0), ANY, |machine, _| {
+        let mut lines: Vec<String> = machine
+            .register_stack_stats()
+            .into_iter()
+            .map(|(name, stats)| format!("{name} {stats}"))
+            .collect();
+        if lines.is_empty() {
+            lines.push(machine.stack_stats().to_string());
+        }
+        for line in lines {
+            writeln!(machine.output(), "{line}").map_err(|e| RuntimeErrorKind::InvalidInput(e.to_string()))?;
+        }
+        Ok(Vec::new())
+    }),
+    ("trace-on", Arity::Fixed(0), ANY, |machine, _| {
+        machine.trace_on();
+        Ok(Vec::new())
+    }),
+    ("trace-off", Arity::Fixed(0), ANY, |machine, _| {
+        machine.trace_off();
+        Ok(Vec::new())
+    }),
+    ("trace-register-on", Arity::Fixed(1), SYMBOL, |machine, operands| {
+        let [name] = operands_of(operands)?;
+        machine.trace_register_on(name.as_symbol()?)?;
+        Ok(Vec::new())
+    }),
+    ("trace-register-off", Arity::Fixed(1), SYMBOL, |machine, operands| {
+        let [name] = operands_of(operands)?;
+        machine.trace_register_off(name.as_symbol()?)?;
+        Ok(Vec::new())
+    }),
+];
+
+pub fn machine_operations() -> Vec<(String, Operation)> {
+    MACHINE_OPERATIONS.iter().map(make_machine_operation).collect()
+}
+
+const ANY: &[OperandKind] = &[];
+const INTEGERS: &[OperandKind] = &[OperandKind::Kind(ValueKind::Integer)];
+
+// name, arity, operand kinds and implementation of every standard operation.
+type Entry = (&'static str, Arity, &'static [OperandKind], PrimitiveFn);
+type MachineEntry = (&'static str, Arity, &'static [OperandKind], MachineFn);
+
+const STANDARD_OPERATIONS: &[Entry] = &[
+    // arithmetic
+    ("+", Arity::Variadic(0), INTEGERS, add),
+    ("-", Arity::Variadic(1), INTEGERS, sub),
+    ("*", Arity::Variadic(0), INTEGERS, mul),
+    ("quotient", Arity::Fixed(2), INTEGERS, quotient),
+    ("remainder", Arity::Fixed(2), INTEGERS, remainder),
+    ("rem", Arity::Fixed(2), INTEGERS, remainder),
+    ("abs", Arity::Fixed(1), INTEGERS, abs),
+    // comparison
+    ("=", Arity::Fixed(2), INTEGERS, |operands| compare(operands, |a, b| a == b)),
+    ("<", Arity::Fixed(2), INTEGERS, |operands| compare(operands, |a, b| a < b)),
+    (">", Arity::Fixed(2), INTEGERS, |operands| compare(operands, |a, b| a > b)),
+    ("<=", Arity::Fixed(2), INTEGERS, |operands| compare(operands, |a, b| a <= b)),
+    (">=", Arity::Fixed(2), INTEGERS, |operands| compare(operands, |a, b| a >= b)),
+    ("eq?", Arity::Fixed(2), ANY, |operands| {
+        let [a, b] = operands_of(operands)?;
+        boolean(a == b)
+    }),
+    // logic
+    ("and", Arity::Variadic(0), ANY, |operands| boolean(operands.iter().all(Value::is_true))),
+    ("or", Arity::Variadic(0), ANY, |operands| boolean(operands.iter().any(Value::is_true))),
+    ("not", Arity::Fixed(1), ANY, |operands| {
+        let [a] = operands_of(operands)?;
+        boolean(!a.is_true())
+    }),
+    // predicates
+    ("zero?", Arity::Fixed(1), INTEGERS, |operands| integer_predicate(operands, |n| n == 0)),
+    ("positive?", Arity::Fixed(1), INTEGERS, |operands| integer_predicate(operands, |n| n > 0)),
+    ("negative?", Arity::Fixed(1), INTEGERS, |operands| integer_predicate(operands, |n| n < 0)),
+    ("even?", Arity::Fixed(1), INTEGERS, |operands| integer_predicate(operands, |n| n % 2 == 0)),
+    ("odd?", Arity::Fixed(1), INTEGERS, |operands| integer_predicate(operands, |n| n % 2 != 0)),
+    ("number?", Arity::Fixed(1), ANY, |operands| kind_predicate(operands, |v| matches!(v, Value::Integer(_)))),
+    ("boolean?", Arity::Fixed(1), ANY, |operands| kind_predicate(operands, |v| matches!(v, Value::Boolean(_)))),
+    ("symbol?", Arity::Fixed(1), ANY, |operands| kind_predicate(operands, |v| matches!(v, Value::Symbol(_)))),
+    ("string?", Arity::Fixed(1), ANY, |operands| kind_predicate(operands, |v| matches!(v, Value::Str(_)))),
+    ("pair?", Arity::Fixed(1), ANY, |operands| kind_predicate(operands, |v| matches!(v, Value::Pair(_)))),
+];
+
+// The standard operations on the machine's input and output.
+const IO_OPERATIONS: &[MachineEntry] = &[
+    ("read", Arity::Fixed(0), ANY, |machine, operands| {
+        let [] = operands_of(operands)?;
+        Ok(vec![read_value(machine.input())?])
+    }),
+    ("print", Arity::Variadic(0), ANY, |machine, operands| {
+        writeln!(machine.output(), "{}", print_values(operands))
+            .map_err(|e| RuntimeErrorKind::InvalidInput(e.to_string()))?;
+        Ok(Vec::new())
+    }),
+];
+
+// Every standard operation, ready to be passed to Machine::make_machine.
+pub fn standard_operations() -> Vec<(String, Operation)> {
+    STANDARD_OPERATIONS
+        .iter()
+        .map(make_operation)
+        .chain(IO_OPERATIONS.iter().map(make_machine_operation))
+        .collect()
+}
+
+// Only the named standard operations, failing on a name the library doesn't have.
+pub fn select_operations(names: &[&str]) -> Result<Vec<(String, Operation)>, AssembleError> {
+    names
+        .iter()
+        .map(|name| {
+            STANDARD_OPERATIONS
+                .iter()
+                .find(|(op_name, ..)| op_name == name)
+                .map(make_operation)
+                .or_else(|| IO_OPERATIONS.iter().find(|(op_name, ..)| op_name == name).map(make_machine_operation))
+                .ok_or(AssembleError::UnknownOperation(name.to_string()))
+        })
+        .collect()
+}
+
+// Operations known by name, to bind the (operations ...) of a machine definition.
+#[derive(Clone, Default)]
+pub struct OperationRegistry {
+    operations: HashMap<String, Operation>,
+}
+
+impl OperationRegistry {
+    pub fn new() -> Self {
+        OperationRegistry::default()
+    }
+    // A registry holding every standard operation.
+    pub fn standard() -> Self {
+        OperationRegistry { operations: standard_operations().into_iter().collect() }
+    }
+    // Add an operation, replacing any other of the same name.
+    pub fn register<Args>(&mut self, name: &str, operation: impl IntoOperation<Args>) -> &mut Self {
+        self.operations.insert(name.to_string(), operation.into_operation());
+        self
+    }
+    pub fn resolve(&self, names: &[impl AsRef<str>]) -> Result<Vec<(String, Operation)>, AssembleError> {
+        names
+            .iter()
+            .map(|name| {
+                let name = name.as_ref();
+                self.operations
+                    .get(name)
+                    .map(|operation| (name.to_string(), operation.clone()))
+                    .ok_or_else(|| AssembleError::UnknownOperation(name.to_string()))
+            })
+            .collect()
+    }
+}
+
+fn make_operation(&(name, arity, operands, f): &Entry) -> (String, Operation) {
+    let signature = Signature::new(arity).with_operands(operands);
+    (name.to_string(), Box::new(Primitive { f, signature }))
+}
+fn make_machine_operation(&(name, arity, operands, f): &MachineEntry) -> (String, Operation) {
+    let signature = Signature::new(arity).with_operands(operands);
+    (name.to_string(), Box::new(MachineOperation { f, signature }))
+}
+
+fn operands_of<const N: usize>(operands: &[Value]) -> Result<&[Value; N], RuntimeErrorKind> {
+    operands
+        .try_into()
+        .map_err(|_| RuntimeErrorKind::OperandCount { expected: N, found: operands.len() })
+}
+fn integer(value: i64) -> Result<Vec<Value>, RuntimeErrorKind> {
+    Ok(vec![Value::Integer(value)])
+}
+fn boolean(value: bool) -> Result<Vec<Value>, RuntimeErrorKind> {
+    Ok(vec![Value::Boolean(value)])
+}
+fn integers(operands: &[Value]) -> Result<Vec<i64>, RuntimeErrorKind> {
+    operands.iter().map(Value::as_integer).collect()
+}
+
+fn add(operands: &[Value]) -> Result<Vec<Value>, RuntimeErrorKind> {
+    integers(operands)?
+        .into_iter()
+        .try_fold(0i64, |sum, n| sum.checked_add(n))
+        .map_or(Err(RuntimeErrorKind::Overflow), integer)
+}
+fn mul(operands: &[Value]) -> Result<Vec<Value>, RuntimeErrorKind> {
+    integers(operands)?
+        .into_iter()
+        .try_fold(1i64, |product, n| product.checked_mul(n))
+        .map_or(Err(RuntimeErrorKind::Overflow), integer)
+}
+// (- a) negates, (- a b c) subtracts b and c from a.
+fn sub(operands: &[Value]) -> Result<Vec<Value>, RuntimeErrorKind> {
+    let numbers = integers(operands)?;
+    let result = match numbers.as_slice() {
+        [] => return Err(RuntimeErrorKind::OperandCount { expected: 1, found: 0 }),
+        [n] => n.checked_neg(),
+        [first, rest @ ..] => rest.iter().try_fold(*first, |difference, n| difference.checked_sub(*n)),
+    };
+    result.map_or(Err(RuntimeErrorKind::Overflow), integer)
+}
+fn quotient(operands: &[Value]) -> Result<Vec<Value>, RuntimeErrorKind> {
+    let [a, b] = operands_of(operands)?;
+    let (a, b) = (a.as_integer()?, b.as_integer()?);
+    if b == 0 {
+        return Err(RuntimeErrorKind::DivisionByZero);
+    }
+    a.checked_div(b).map_or(Err(RuntimeErrorKind::Overflow), integer)
+}
+// The remainder takes the sign of the dividend, like Scheme's remainder.
+fn remainder(operands: &[Value]) -> Result<Vec<Value>, RuntimeErrorKind> {
+    let [a, b] = operands_of(operands)?;
+    let (a, b) = (a.as_integer()?, b.as_integer()?);
+    if b == 0 {
+        return Err(RuntimeErrorKind::DivisionByZero);
+    }
+    a.checked_rem(b).map_or(Err(RuntimeErrorKind::Overflow), integer)
+}
+fn abs(operands: &[Value]) -> Result<Vec<Value>, RuntimeErrorKind> {
+    let [a] = operands_of(operands)?;
+    a.as_integer()?.checked_abs().map_or(Err(RuntimeErrorKind::Overflow), integer)
+}
+
+fn compare(operands: &[Value], cmp: fn(i64, i64) -> bool) -> Result<Vec<Value>, RuntimeErrorKind> {
+    let [a, b] = operands_of(operands)?;
+    boolean(cmp(a.as_integer()?, b.as_integer()?))
+}
+fn integer_predicate(operands: &[Value], test: fn(i64) -> bool) -> Result<Vec<Value>, RuntimeErrorKind> {
+    let [a] = operands_of(operands)?;
+    boolean(test(a.as_integer()?))
+}
+fn kind_predicate(operands: &[Value], test: fn(&Value) -> bool) -> Result<Vec<Value>, RuntimeErrorKind> {
+    let [a] = operands_of(operands)?;
+    boolean(test(a))
+}
+
+// Read one line and turn it into a value, using the same syntax as (const ...).
+fn read_value(input: &mut dyn BufRead) -> Result<Value, RuntimeErrorKind> {
+    let mut line = String::new();
+    let read_bytes = input
+        .read_line(&mut line)
+        .map_err(|e| RuntimeErrorKind::InvalidInput(e.to_string()))?;
+    if read_bytes == 0 {
+        return Err(RuntimeErrorKind::InvalidInput("end of input".to_string()));
+    }
+    parse_value(&line).map_err(|e| RuntimeErrorKind::InvalidInput(e.message))
+}
+fn print_values(operands: &[Value]) -> String {
+    operands.iter().map(Value::to_string).collect::<Vec<String>>().join(" ")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{print_values, read_value, select_operations, standard_operations, STANDARD_OPERATIONS};
+    use crate::machine::fixtures::GCD;
+    use crate::machine::{AssembleError, Halted, Machine, MemoryOutput, RuntimeErrorKind, Value};
+
+    fn apply(name: &str, operands: &[Value]) -> Result<Vec<Value>, RuntimeErrorKind> {
+        let (.., f) = STANDARD_OPERATIONS.iter().find(|(op_name, ..)| *op_name == name).unwrap();
+        f(operands)
+    }
+    fn int(n: i64) -> Value {
+        Value::Integer(n)
+    }
+    fn ok(value: Value) -> Result<Vec<Value>, RuntimeErrorKind> {
+        Ok(vec![value])
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(apply("+", &[int(1), int(2), int(3)]), ok(int(6)));
+        assert_eq!(apply("+", &[]), ok(int(0)));
+        assert_eq!(apply("-", &[int(10), int(3), int(2)]), ok(int(5)));
+        assert_eq!(apply("-", &[int(4)]), ok(int(-4)));
+        assert_eq!(apply("*", &[int(4), int(5)]), ok(int(20)));
+        assert_eq!(apply("quotient", &[int(17), int(5)]), ok(int(3)));
+        assert_eq!(apply("rem", &[int(206), int(40)]), ok(int(6)));
+        assert_eq!(apply("remainder", &[int(-7), int(2)]), ok(int(-1)));
+        assert_eq!(apply("abs", &[int(-7)]), ok(int(7)));
+    }
+
+    #[test]
+    fn test_arithmetic_errors() {
+        assert_eq!(apply("rem", &[int(1), int(0)]), Err(RuntimeErrorKind::DivisionByZero));
+        assert_eq!(apply("quotient", &[int(1), int(0)]), Err(RuntimeErrorKind::DivisionByZero));
+        assert_eq!(apply("*", &[int(i64::MAX), int(2)]), Err(RuntimeErrorKind::Overflow));
+        assert_eq!(apply("-", &[]), Err(RuntimeErrorKind::OperandCount { expected: 1, found: 0 }));
+        assert_eq!(apply("rem", &[int(1)]), Err(RuntimeErrorKind::OperandCount { expected: 2, found: 1 }));
+        assert!(matches!(apply("+", &[int(1), Value::Boolean(true)]), Err(RuntimeErrorKind::TypeMismatch { .. })));
+    }
+
+    #[test]
+    fn test_comparison() {
+        assert_eq!(apply("=", &[int(3), int(3)]), ok(Value::Boolean(true)));
+        assert_eq!(apply("<", &[int(3), int(4)]), ok(Value::Boolean(true)));
+        assert_eq!(apply(">", &[int(3), int(4)]), ok(Value::Boolean(false)));
+        assert_eq!(apply("<=", &[int(4), int(4)]), ok(Value::Boolean(true)));
+        assert_eq!(apply(">=", &[int(3), int(4)]), ok(Value::Boolean(false)));
+        let done = Value::Symbol("done".to_string());
+        assert_eq!(apply("eq?", &[done.clone(), done]), ok(Value::Boolean(true)));
+        assert_eq!(apply("eq?", &[int(1), Value::Boolean(true)]), ok(Value::Boolean(false)));
+    }
+
+    #[test]
+    fn test_logic() {
+        let (t, f) = (Value::Boolean(true), Value::Boolean(false));
+        assert_eq!(apply("and", &[t.clone(), int(0)]), ok(t.clone()));
+        assert_eq!(apply("and", &[t.clone(), f.clone()]), ok(f.clone()));
+        assert_eq!(apply("or", &[f.clone(), f.clone()]), ok(f.clone()));
+        assert_eq!(apply("or", &[f.clone(), t.clone()]), ok(t.clone()));
+        assert_eq!(apply("not", std::slice::from_ref(&f)), ok(t.clone()));
+        assert_eq!(apply("not", &[int(0)]), ok(f));
+    }
+
+    #[test]
+    fn test_predicates() {
+        let yes = || ok(Value::Boolean(true));
+        let no = || ok(Value::Boolean(false));
+        assert_eq!(apply("zero?", &[int(0)]), yes());
+        assert_eq!(apply("zero?", &[int(2)]), no());
+        assert_eq!(apply("positive?", &[int(2)]), yes());
+        assert_eq!(apply("negative?", &[int(2)]), no());
+        assert_eq!(apply("even?", &[int(-4)]), yes());
+        assert_eq!(apply("odd?", &[int(-3)]), yes());
+        assert_eq!(apply("number?", &[Value::Symbol("x".to_string())]), no());
+        assert_eq!(apply("boolean?", &[Value::Boolean(false)]), yes());
+        assert_eq!(apply("symbol?", &[Value::Symbol("x".to_string())]), yes());
+        assert_eq!(apply("string?", &[Value::Str("x".to_string())]), yes());
+        assert_eq!(apply("pair?", &[Value::Pair(0)]), yes());
+    }
+
+    #[test]
+    fn test_read_and_print() {
+        let mut input = "42\n  #f\nhello\n\"a b\"\n(1 2)\n".as_bytes();
+        assert_eq!(read_value(&mut input), Ok(int(42)));
+        assert_eq!(read_value(&mut input), Ok(Value::Boolean(false)));
+        assert_eq!(read_value(&mut input), Ok(Value::Symbol("hello".to_string())));
+        assert_eq!(read_value(&mut input), Ok(Value::Str("a b".to_string())));
+        assert!(matches!(read_value(&mut input), Err(RuntimeErrorKind::InvalidInput(_))));
+        assert_eq!(read_value(&mut input), Err(RuntimeErrorKind::InvalidInput("end of input".to_string())));
+
+        assert_eq!(print_values(&[int(1), Value::Str("a".to_string()), Value::Boolean(true)]), "1 \"a\" #t");
+
+        let controller = "(assign a (op read)) (perform (op print) (reg a) (const done))";
+        let mut machine = Machine::make_machine(&["a"], standard_operations(), controller).unwrap();
+        let output = MemoryOutput::default();
+        machine.set_input(Box::new("42\n".as_bytes()));
+        machine.set_output(Box::new(output.clone()));
+        assert_eq!(machine.start(), Ok(Halted::Done));
+        assert_eq!(output.take(), "42 done\n");
+        assert_eq!(machine.start().unwrap_err().kind.root_cause(), &RuntimeErrorKind::InvalidInput("end of input".to_string()));
+    }
+
+    #[test]
+    fn test_select_operations() {
+        let names: Vec<String> = select_operations(&["rem", "="]).unwrap().into_iter().map(|(name, _)| name).collect();
+        assert_eq!(names, vec!["rem", "="]);
+        assert_eq!(select_operations(&["rem", "gcd"]).err(), Some(AssembleError::UnknownOperation("gcd".to_string())));
+    }
+
+    #[test]
+    fn test_gcd_with_standard_operations() {
+        let mut machine = Machine::make_machine(&["a", "b", "t"], standard_operations(), GCD).unwrap();
+        machine.set_register_contents("a", 206).unwrap();
+        machine.set_register_contents("b", 40).unwrap();
+        assert_eq!(machine.start(), Ok(Halted::Done));
+        assert_eq!(machine.get_register_contents("a"), Ok(int(2)));
+    }
+}
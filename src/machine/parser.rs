@@ -1,3 +1,14 @@
+use std::fmt;
+
+mod lexer;
+mod reader;
+pub use lexer::{tokenize, Token, TokenKind};
+pub use reader::{read, SExpr, SExprKind};
+
+use super::diagnostic::Span;
+use super::error::ParseError;
+use super::value::Value;
+
 //The assemble function will take the Vec<Expr> as parameters.
 pub type ControllerText = Vec<Expr>;
 #[derive(Debug)]
@@ -5,23 +16,53 @@ pub enum Expr {
     Instruction(Instruction),
     Label(Label),
 } 
+impl Expr {
+    pub fn span(&self) -> Span {
+        match self {
+            Expr::Instruction(inst) => inst.span(),
+            Expr::Label(label) => label.span(),
+        }
+    }
+}
 
+// An instruction together with where it was written in the controller text.
+#[derive(Debug)]
+pub struct Instruction {
+    kind: InstructionKind,
+    span: Span,
+}
+impl Instruction {
+    pub fn kind(&self) -> &InstructionKind {
+        &self.kind
+    }
+    pub fn span(&self) -> Span {
+        self.span
+    }
+}
 #[derive(Debug)]
-pub enum Instruction {
+pub enum InstructionKind {
     Assign {target_reg: String, val_expr: ValueExpr},
     Test(OpreationExpr),
     Branch(Label),
-    // Goto(PrimitiveExpr),
-    // Save {reg: String},
-    // Restore {reg: String},
-    // Perform(OpreationExpr),
-}
-// impl Instruction {
-//     fn make_instruction() // Construct instruction by combining resources
-//     fn instruction_text(&self) -> String {
-//     } // Implement as String output
-//     fn instruction_execution_proc() // No need to construct closures anymore, directly change to execute corresponding procedure
-// }
+    Goto(PrimitiveExpr),
+    Save {reg: String},
+    Restore {reg: String},
+    Perform(OpreationExpr),
+}
+impl InstructionKind {
+    // The keyword the instruction starts with.
+    pub fn name(&self) -> &'static str {
+        match self {
+            InstructionKind::Assign { .. } => "assign",
+            InstructionKind::Test(_) => "test",
+            InstructionKind::Branch(_) => "branch",
+            InstructionKind::Goto(_) => "goto",
+            InstructionKind::Save { .. } => "save",
+            InstructionKind::Restore { .. } => "restore",
+            InstructionKind::Perform(_) => "perform",
+        }
+    }
+}
 #[derive(Debug)]
 pub enum ValueExpr {
     OpreationExpr(OpreationExpr),
@@ -31,7 +72,6 @@ pub enum ValueExpr {
 pub struct OpreationExpr {
     name: String,
     oprands: Vec<ValueExpr>,
-    arity: usize,
 }
 impl OpreationExpr {
     pub fn name(&self) -> &str {
@@ -40,287 +80,329 @@ impl OpreationExpr {
     pub fn oprands(&self) -> &[ValueExpr] {
         self.oprands.as_slice()
     }
-    pub fn arity(&self) -> usize {
-        self.arity
-    }
 }
 #[derive(Debug)]
 pub enum PrimitiveExpr {
-    Constant(u32),
+    Constant(Value),
     Label(Label),
     Register(String),
 }
 #[derive(Debug, Clone, PartialEq, Eq)]
-pub struct Label(String);
+pub struct Label {
+    name: String,
+    span: Span,
+}
 impl Label {
     pub fn get_name(&self) -> String {
-        self.0.clone()
+        self.name.clone()
+    }
+    pub fn span(&self) -> Span {
+        self.span
     }
 }
-pub fn parse(controller_text: &str) -> Result<(&str, ControllerText), String> {
-    let mut remaining = controller_text.trim();
-    let mut exprs = Vec::new();
-    while !remaining.trim().is_empty() {
-        let (new_remaining, expr) = parse_expr(remaining)?;
-        exprs.push(expr);
 
-        remaining = new_remaining;
+// The instruction text is printed back in controller syntax, e.g. "(assign n (op -) (reg n) (const 1))".
+impl fmt::Display for Instruction {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.kind)
     }
-    Ok((remaining, exprs))
 }
-
-fn parse_expr(input: &str) -> Result<(&str, Expr), String> {
-    let input = input.trim();
-    if input.starts_with("(") {
-        parse_instruction(input)
-            .map(|(remaining,inst)| (remaining, Expr::Instruction(inst)))
-    } else {
-        parse_label(input)
-            .map(|(remaining, label)| (remaining, Expr::Label(label)))
-    }
-}
-fn parse_label(input: &str) -> Result<(&str, Label), String> {
-    let (remaining, ident) = ident_parser(input.trim()).map_err(|_e| "Expected identifier for label")?;
-    Ok((remaining, Label(ident.to_string())))
-}
-fn parse_instruction(input: &str) -> Result<(&str, Instruction), String> {
-    let input = input.trim_start_matches("(").trim();
-    if input.starts_with("assign") {
-        parse_assign(input)
-    } else if input.starts_with("test") {
-        parse_test(input)
-    } else if input.starts_with("branch") {
-        parse_branch(input)
-    } else {
-        Err(format!("Failed to parse instruction: {}", 
-            input.split_whitespace().next().unwrap_or("instruction not found.")
-        ))
+impl fmt::Display for InstructionKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            InstructionKind::Assign { target_reg, val_expr } => write!(f, "(assign {target_reg} {val_expr})"),
+            InstructionKind::Test(cond) => write!(f, "(test {cond})"),
+            InstructionKind::Branch(label) => write!(f, "(branch (label {label}))"),
+            InstructionKind::Goto(dest) => write!(f, "(goto {dest})"),
+            InstructionKind::Save { reg } => write!(f, "(save {reg})"),
+            InstructionKind::Restore { reg } => write!(f, "(restore {reg})"),
+            InstructionKind::Perform(op) => write!(f, "(perform {op})"),
+        }
     }
 }
-
-// match a identifier and return it as a string
-fn ident_parser(input: &str) -> Result<(&str, String), String> {
-    let mut chars = input.chars();
-    let mut ident = String::new();
-    let is_allowed_in_ident= |c: char| -> bool  {
-        matches!(c, '_' | '-' | '=' | '>' | '<' | '?' | '+' | '*' | '/' | '&' | '^' | '%' | '!')
-    };
-    while let Some(c) = chars.next() {
-        if c.is_alphabetic() || is_allowed_in_ident(c) {
-            ident.push(c);
-        } else {
-            break;
+impl fmt::Display for ValueExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ValueExpr::OpreationExpr(op) => write!(f, "{op}"),
+            ValueExpr::PrimitiveExpr(primitive) => write!(f, "{primitive}"),
         }
     }
-    if ident.is_empty() {
-        Err("Expected identifier".to_string())
-    } else {
-        Ok((&input[ident.len()..].trim(), ident))
+}
+impl fmt::Display for OpreationExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(op {})", self.name)?;
+        for oprand in &self.oprands {
+            write!(f, " {oprand}")?;
+        }
+        Ok(())
     }
 }
-fn number_parser(input: &str) -> Result<(&str, u32), String> {
-    let mut chars = input.chars();
-    let mut num = String::new();
-    while let Some(c) = chars.next() {
-        if c.is_numeric() {
-            num.push(c);
-        } else {
-            break;
+impl fmt::Display for PrimitiveExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            PrimitiveExpr::Constant(value) => write!(f, "(const {value})"),
+            PrimitiveExpr::Label(label) => write!(f, "(label {label})"),
+            PrimitiveExpr::Register(reg) => write!(f, "(reg {reg})"),
         }
     }
-    if num.is_empty() {
-        Err("Expected Value".to_string())
-    } else {
-        let value = num.parse()
-            .map_err(|e| format!("Failed to parse the value '{num}' : {e}",))?;
-        Ok((&input[num.len()..].trim(), value))
-    }
-}
-fn parse_reg(input: &str) -> Result<(&str, String), String> {
-    let input = input.trim_start_matches("reg").trim();
-
-    let (input, name) = ident_parser(input).map_err(|e| {
-        format!("{e:?}: register expression expects a name after 'reg' like (reg name)")
-    })?;
-    let input = input.starts_with(')')
-        .then(|| input[1..].trim())
-        .ok_or("Expects a ')' at the end of the register expression")?;
-    
-    Ok((input, name))
-}
-fn parse_const(input: &str) -> Result<(&str, u32), String> {
-    let input = input.trim_start_matches("const").trim();
-
-    let (input, value) = number_parser(input).map_err(|e| {
-        format!("{e:?}: constant expression expects a const value after 'constant' like (const value)")
-    })?;
-    let input = input.starts_with(')')
-        .then(|| input[1..].trim())
-        .ok_or("Expects a ')' at the end of the constant expression")?;
-    
-    Ok((input, value))
-}
-fn parse_label_expr(input: &str) -> Result<(&str, Label), String> {
-    let input = input.trim_start_matches("label").trim();
-
-    let (input, label) = ident_parser(input).map_err(|e| {
-        format!("{e:?}: label expression expects a label identity after 'label' like (label ident)")
-    })?;
-    let input = input.starts_with(')')
-        .then(|| input[1..].trim())
-        .ok_or("Expects a ')' at the end of the constant expression")?;
-    
-    Ok((input, Label(label)))
-}
-fn parse_primitive_expr(input: &str) -> Result<(&str, PrimitiveExpr), String> {
-    let input = input.trim_start_matches('(').trim();
-    if input.starts_with("label") {
-        parse_label_expr(input).map(|(remaining, label)| (remaining, PrimitiveExpr::Label(label)))
-    } else if input.starts_with("reg") {
-        parse_reg(input).map(|(remaining, reg)| (remaining, PrimitiveExpr::Register(reg)))
-    } else if input.starts_with("const") {
-        parse_const(input).map(|(remaining, value)| (remaining, PrimitiveExpr::Constant(value)))
-    } else {
-        Err("Invalid primitive expression".to_string())
-    }
-}
-
-// Example to be parsed: "op add (reg a) (reg b))"
-//     Notice that the beginning '(' is already stripped
-fn parse_operation(input: &str) -> Result<(&str, OpreationExpr), String> {
-    let input = input.trim_start_matches("op").trim();
-
-    let (input, operation) = ident_parser(input).map_err(|e| {
-        format!("{e:?}: operation expression expects a name after 'op' like (op operation)")
-    })?;
-
-    let input = input.starts_with(')')
-        .then(|| input[1..].trim())
-        .ok_or("Expects a ')' at the end of the constant expression")?;
-
-    if let Some(mut input) = input.starts_with('(')
-        .then(|| input[1..].trim()) {
-            if input.starts_with("op") {
-                Err("Nested op is not allowed!".to_string())
-            } else {
-                let (mut input, first_param) = parse_primitive_expr(input)?;
-                let mut oprands = vec![ValueExpr::PrimitiveExpr(first_param)];
-                while input.starts_with('(') {
-                    let (new_input, primitive) = parse_primitive_expr(input)?;
-                    input = new_input;
-                    oprands.push(ValueExpr::PrimitiveExpr(primitive));
-                }
-                
-                input.trim().strip_prefix(')')
-                    .ok_or("Expects a ')' at the end of one expression including op".to_string())
-                    .map(|remaining| (remaining, OpreationExpr { 
-                        name: operation.to_string(),
-                        arity: oprands.len(),
-                        oprands
-                    }))
-                
-            }
-    } else {
-        //The operation does not have any operands here.
-        Ok((input, OpreationExpr { 
-            name: operation.to_string(),
-            oprands: Vec::new(),
-            arity: 0
-        }))
-    }
-}
-fn parse_value_expr(input: &str) -> Result<(&str, ValueExpr), String> {
-    let input = input.trim_start_matches('(').trim();
-    if input.starts_with("op") {
-        parse_operation(input)
-            .map(|(remaining, op)| (remaining, ValueExpr::OpreationExpr(op)))
-    } else if input.starts_with("reg") {
-        parse_reg(input).and_then(|(remaining, reg)| {
-            if let Some(remaining) = remaining.strip_prefix(')') {
-                Ok((remaining, ValueExpr::PrimitiveExpr(PrimitiveExpr::Register(reg))))
-            } else {
-                Err("Expects a ')' at the end of the assign expression".to_string())
-            }
-        })
-    } else if input.starts_with("const") {
-        parse_const(input).and_then(|(remaining, value)| {
-            if let Some(remaining) = remaining.strip_prefix(')') {
-                Ok((remaining, ValueExpr::PrimitiveExpr(PrimitiveExpr::Constant(value))))
-            } else {
-                Err("Expects a ')' at the end of the assign expression".to_string())
-            }
-        })
-    } else if input.starts_with("label") {
-        parse_label(input).and_then(|(remaining, label)| {
-            if let Some(remaining) = remaining.strip_prefix(')') {
-                Ok((remaining, ValueExpr::PrimitiveExpr(PrimitiveExpr::Label(label))))
-            } else {
-                Err("Expects a ')' at the end of the assign expression".to_string())
+}
+impl fmt::Display for Label {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.name)
+    }
+}
+
+pub fn parse(controller_text: &str) -> Result<(&str, ControllerText), ParseError> {
+    let exprs = read(controller_text)?
+        .iter()
+        .map(parse_expr)
+        .collect::<Result<ControllerText, ParseError>>()?;
+    Ok((&controller_text[controller_text.len()..], exprs))
+}
+
+// A machine described in one file, like
+// (define-machine gcd (registers a b t) (operations rem =) (controller ...)).
+// The operations are only names here, bound to implementations when the machine is made.
+#[derive(Debug)]
+pub struct MachineDefinition {
+    pub name: String,
+    pub registers: Vec<String>,
+    pub operations: Vec<String>,
+    pub controller: ControllerText,
+    pub span: Span,
+}
+
+// Every define-machine form of `source`.
+pub fn parse_definitions(source: &str) -> Result<Vec<MachineDefinition>, ParseError> {
+    read(source)?.iter().map(parse_definition).collect()
+}
+
+fn parse_definition(expr: &SExpr) -> Result<MachineDefinition, ParseError> {
+    let Some([name, sections @ ..]) = tagged(expr, "define-machine") else {
+        return Err(error(expr, "Expects a machine definition like (define-machine name (registers ...) (operations ...) (controller ...))"));
+    };
+    let name = ident_parser(name, "Expects the name of the machine")?;
+
+    let (mut registers, mut operations, mut controller) = (None, None, None);
+    for section in sections {
+        let Some([head, items @ ..]) = section.as_list() else {
+            return Err(error(section, "Expects a (registers ...), (operations ...) or (controller ...) section"));
+        };
+        let names = || -> Result<Vec<String>, ParseError> {
+            items.iter().map(|item| ident_parser(item, "Expects a name")).collect()
+        };
+        let duplicate = match head.as_symbol() {
+            Some("registers") => registers.replace(names()?).is_some(),
+            Some("operations") => operations.replace(names()?).is_some(),
+            Some("controller") => {
+                let text = items.iter().map(parse_expr).collect::<Result<ControllerText, ParseError>>()?;
+                controller.replace(text).is_some()
             }
-        })
+            _ => return Err(error(head, format!("Unknown section {head} in machine {name}"))),
+        };
+        if duplicate {
+            return Err(error(head, format!("Duplicate {head} section in machine {name}")));
+        }
+    }
+
+    let controller = controller.ok_or_else(|| error(expr, format!("Machine {name} has no (controller ...) section")))?;
+    Ok(MachineDefinition {
+        name,
+        registers: registers.unwrap_or_default(),
+        operations: operations.unwrap_or_default(),
+        controller,
+        span: expr.span,
+    })
+}
+
+fn error(expr: &SExpr, message: impl Into<String>) -> ParseError {
+    ParseError { span: expr.span, message: message.into() }
+}
+
+// The items after the tag of a list like (tag item ...), if `expr` is one.
+fn tagged<'e>(expr: &'e SExpr, tag: &str) -> Option<&'e [SExpr]> {
+    match expr.as_list() {
+        Some([head, rest @ ..]) if head.as_symbol() == Some(tag) => Some(rest),
+        _ => None,
+    }
+}
+
+// A label or a register name, which must be a symbol.
+fn ident_parser(expr: &SExpr, message: &str) -> Result<String, ParseError> {
+    expr.as_symbol()
+        .map(str::to_string)
+        .ok_or_else(|| error(expr, message))
+}
+
+// Top-level symbols are labels, top-level lists are instructions.
+fn parse_expr(expr: &SExpr) -> Result<Expr, ParseError> {
+    match &expr.kind {
+        SExprKind::List(_) => parse_instruction(expr).map(Expr::Instruction),
+        SExprKind::Symbol(name) => Ok(Expr::Label(Label { name: name.clone(), span: expr.span })),
+        _ => Err(error(expr, "Expected identifier for label")),
+    }
+}
+
+fn parse_instruction(expr: &SExpr) -> Result<Instruction, ParseError> {
+    let Some([head, rest @ ..]) = expr.as_list() else {
+        return Err(error(expr, "Failed to parse instruction: instruction not found."));
+    };
+    let kind = match head.as_symbol() {
+        Some("assign") => parse_assign(expr, rest),
+        Some("test") => parse_test(expr, rest),
+        Some("branch") => parse_branch(expr, rest),
+        Some("goto") => parse_goto(expr, rest),
+        Some("save") => ident_pair(expr, rest, "save expression expects a register name like (save name)")
+            .map(|reg| InstructionKind::Save { reg }),
+        Some("restore") => ident_pair(expr, rest, "restore expression expects a register name like (restore name)")
+            .map(|reg| InstructionKind::Restore { reg }),
+        Some("perform") => parse_perform(expr, rest),
+        _ => Err(error(head, format!("Failed to parse instruction: {head}"))),
+    }?;
+    Ok(Instruction { kind, span: expr.span })
+}
+
+// The single name of forms like (save name) or (reg name).
+fn ident_pair(expr: &SExpr, rest: &[SExpr], message: &str) -> Result<String, ParseError> {
+    match rest {
+        [name] => ident_parser(name, message),
+        [_, extra, ..] => Err(error(extra, message)),
+        [] => Err(error(expr, message)),
+    }
+}
+
+fn parse_label_expr(expr: &SExpr) -> Option<Result<Label, ParseError>> {
+    let rest = tagged(expr, "label")?;
+    let message = "label expression expects a label identity after 'label' like (label ident)";
+    Some(match rest {
+        [name] => ident_parser(name, message).map(|name_text| Label { name: name_text, span: name.span }),
+        [_, extra, ..] => Err(error(extra, message)),
+        [] => Err(error(expr, message)),
+    })
+}
+
+fn parse_primitive_expr(expr: &SExpr) -> Result<PrimitiveExpr, ParseError> {
+    if let Some(label) = parse_label_expr(expr) {
+        label.map(PrimitiveExpr::Label)
+    } else if let Some(rest) = tagged(expr, "reg") {
+        ident_pair(expr, rest, "register expression expects a name after 'reg' like (reg name)")
+            .map(PrimitiveExpr::Register)
+    } else if let Some(rest) = tagged(expr, "const") {
+        parse_const(expr, rest).map(PrimitiveExpr::Constant)
+    } else if tagged(expr, "op").is_some() {
+        Err(error(expr, "Nested op is not allowed!"))
     } else {
-        Err("Invalid values".to_string())
+        Err(error(expr, "Invalid primitive expression"))
     }
 }
 
-fn parse_assign(input: &str) -> Result<(&str, Instruction), String> {
-    let input = input.trim_start_matches("assign").trim();
-    let (target, input ) = input.split_once(' ')
-        .ok_or("Assign expects a target register")?;
-    let input = input.trim();
+// Constants are integers, strings, booleans (#t, #f, true, false) or any other symbol.
+pub fn parse_datum(expr: &SExpr) -> Option<Value> {
+    match &expr.kind {
+        SExprKind::Integer(number) => Some(Value::Integer(*number)),
+        SExprKind::Str(text) => Some(Value::Str(text.clone())),
+        SExprKind::Symbol(name) => Some(match name.as_str() {
+            "#t" | "true" => Value::Boolean(true),
+            "#f" | "false" => Value::Boolean(false),
+            _ => Value::Symbol(name.clone()),
+        }),
+        SExprKind::List(_) => None,
+    }
+}
 
-    if !input.starts_with("(") { return Err("Assign expects a value".to_string()); };
-    let (input , val) = parse_value_expr(input)
-        .map_err(|e| format!("Invalid value expression: {}", e))?;
+// A single value written like the operand of (const ...), e.g. typed in by a user.
+pub fn parse_value(text: &str) -> Result<Value, ParseError> {
+    let exprs = read(text)?;
+    match exprs.as_slice() {
+        [expr] => parse_datum(expr).ok_or_else(|| error(expr, format!("Expects a value, but got {expr}"))),
+        _ => Err(ParseError {
+            span: Span::locate(text, 0, text.len()),
+            message: format!("Expects a single value, but got '{}'", text.trim()),
+        }),
+    }
+}
+
+fn parse_const(expr: &SExpr, rest: &[SExpr]) -> Result<Value, ParseError> {
+    let message = "constant expression expects a const value after 'const' like (const value)";
+    match rest {
+        [value] => parse_datum(value).ok_or_else(|| error(value, message)),
+        [_, extra, ..] => Err(error(extra, message)),
+        [] => Err(error(expr, message)),
+    }
+}
 
-    Ok((input, Instruction::Assign { 
-        target_reg: target.to_string(), 
-        val_expr: val
-    }))
+// Example to be parsed: the items after the tag of
+//     "(test (op =) (reg b) (const 0))", that is "(op =) (reg b) (const 0)"
+fn parse_operation(expr: &SExpr, items: &[SExpr], message: &str) -> Result<OpreationExpr, ParseError> {
+    let Some((op, oprands)) = items.split_first() else {
+        return Err(error(expr, message));
+    };
+    let Some(rest) = tagged(op, "op") else {
+        return Err(error(op, message));
+    };
+    let name = ident_pair(op, rest, "operation expression expects a name after 'op' like (op operation)")?;
+    let oprands = oprands
+        .iter()
+        .map(|oprand| parse_primitive_expr(oprand).map(ValueExpr::PrimitiveExpr))
+        .collect::<Result<Vec<ValueExpr>, ParseError>>()?;
+    Ok(OpreationExpr { name, oprands })
 }
 
-// Example to be parsed: " test (op =) (reg b) (const 0)) "
-// Notice that the beginning '(' is already stripped and 
-//     the trailing ')' will be stripped by parse_operation().
-fn parse_test(input: &str) -> Result<(&str, Instruction), String> {
-    let input = input.trim_start_matches("test").trim();
-    match input.strip_prefix('(') { 
-        None => Err("Test expects an operation verrifying condition".to_string()),
-        Some(input) => {
-            let (input, cond) = parse_operation(input)?;
-            Ok((input.trim(), Instruction::Test(cond)))
+// Example to be parsed: "(assign t (op rem) (reg a) (reg b))" or "(assign a (reg b))"
+fn parse_assign(expr: &SExpr, rest: &[SExpr]) -> Result<InstructionKind, ParseError> {
+    let Some((target, value)) = rest.split_first() else {
+        return Err(error(expr, "Assign expects a target register"));
+    };
+    let target_reg = ident_parser(target, "Assign expects a target register")?;
+    let val_expr = match value {
+        [] => return Err(error(expr, "Assign expects a value")),
+        [first, ..] if tagged(first, "op").is_some() => {
+            ValueExpr::OpreationExpr(parse_operation(expr, value, "Assign expects a value")?)
         }
+        [primitive] => ValueExpr::PrimitiveExpr(parse_primitive_expr(primitive)?),
+        [_, extra, ..] => return Err(error(extra, "Invalid value expression: only an operation takes operands")),
+    };
+    Ok(InstructionKind::Assign { target_reg, val_expr })
+}
+
+fn parse_test(expr: &SExpr, rest: &[SExpr]) -> Result<InstructionKind, ParseError> {
+    parse_operation(expr, rest, "Test expects an operation verrifying condition").map(InstructionKind::Test)
+}
+
+fn parse_branch(expr: &SExpr, rest: &[SExpr]) -> Result<InstructionKind, ParseError> {
+    let message = "The label in branch expression expects a lable tag like (label label_name)";
+    match rest {
+        [dest] => parse_label_expr(dest)
+            .unwrap_or_else(|| Err(error(dest, message)))
+            .map(InstructionKind::Branch),
+        [_, extra, ..] => Err(error(extra, message)),
+        [] => Err(error(expr, "Branch expression expects a label")),
     }
 }
 
-fn parse_branch(input: &str) -> Result<(&str, Instruction), String> {
-    let input = input.trim_start_matches("branch").trim();
-    match input.strip_prefix('(') { 
-        None => Err("Branch expression expects a label".to_string()),
-        Some(input) => {  
-            match ident_parser(input) {
-                Err(e) => 
-                    Err("The label in branch expression expects a lable tag like (label label_name)".to_string()),
-                Ok((input, tag)) if tag != "label" => 
-                    Err("The label in branch expression expects a lable tag like (label label_name)".to_string()),
-                Ok((input, tag)) => {
-                    let (input, label) = parse_label_expr(input.trim())?;
-                    if let Some(input) = input.trim().strip_prefix(')') {
-                        Ok((input, Instruction::Branch(label)))
-                    } else {
-                        Err("Expects a ')' at the end of the branch expression".to_string())
-                    }
-                }
-            } 
-
-            
-        }
+// Example to be parsed: "(goto (label here))" or "(goto (reg continue))"
+fn parse_goto(expr: &SExpr, rest: &[SExpr]) -> Result<InstructionKind, ParseError> {
+    let message = "Goto expects a destination like (label name) or (reg name)";
+    match rest {
+        [dest] => match parse_primitive_expr(dest)? {
+            PrimitiveExpr::Constant(_) => Err(error(dest, "The destination of goto should be a label or a register")),
+            dest => Ok(InstructionKind::Goto(dest)),
+        },
+        [_, extra, ..] => Err(error(extra, message)),
+        [] => Err(error(expr, message)),
     }
 }
 
+// Example to be parsed: "(perform (op print) (reg a))"
+fn parse_perform(expr: &SExpr, rest: &[SExpr]) -> Result<InstructionKind, ParseError> {
+    parse_operation(expr, rest, "Perform expects an operation like (perform (op name) ...)").map(InstructionKind::Perform)
+}
+
 #[cfg(test)]
 mod tests {
-    use super::parse;
+    use super::{parse, parse_definitions, Expr};
+    use crate::machine::fixtures::GCD;
+    use crate::machine::diagnostic::Span;
 
     #[test]
     fn test_parse_expr1() {
@@ -339,4 +421,144 @@ mod tests {
             Err(e) => println!("Error: {}", e),
         }
     }
+
+    #[test]
+    fn test_parse_stack_and_jump_instructions() {
+        let input =
+        "fib-loop
+            (save continue)
+            (assign continue (label afterfib-n-1))
+            (perform (op print) (reg n))
+            (perform (op initialize-stack))
+            (restore continue)
+            (goto (reg continue))
+            (goto (label fib-loop))";
+
+        let (remaining, exprs) = parse(input).unwrap();
+        assert!(remaining.is_empty());
+        let rendered = format!("{:?}", exprs);
+        assert_eq!(exprs.len(), 8);
+        assert!(rendered.contains("Save { reg: \"continue\" }"));
+        assert!(rendered.contains("name: \"afterfib-n-1\""));
+        assert!(rendered.contains("Perform(OpreationExpr { name: \"initialize-stack\", oprands: [] })"));
+        assert!(rendered.contains("Goto(Register(\"continue\"))"));
+    }
+
+    #[test]
+    fn test_parse_error_reports_position() {
+        let input = "start\n  (assign a (const 1))\n  (jump (label start))";
+        let error = parse(input).unwrap_err();
+        assert_eq!(error.message, "Failed to parse instruction: jump");
+        assert_eq!(error.span, Span { start: 32, end: 36, line: 3, column: 4 });
+    }
+
+    #[test]
+    fn test_parse_error_points_at_missing_paren() {
+        let input = "gcd\n  (test (op =) (reg b) (const 0))\n  (assign a (reg b)";
+        let error = parse(input).unwrap_err();
+        assert_eq!(error.message, "Expects a ')' to close this '('");
+        assert_eq!((error.span.line, error.span.column), (3, 3));
+        assert_eq!(error.render(input), "\
+error: Expects a ')' to close this '('
+ --> 3:3
+  |
+3 |   (assign a (reg b)
+  |   ^
+");
+    }
+
+    #[test]
+    fn test_parse_register_copy_digits_and_comments() {
+        let input = "; fib controller fragment
+          afterfib-n-1                     ; upon return, val contains Fib(n - 1)
+            (assign n (reg val))
+            (assign val2 (op +) (reg val) (reg n2))";
+
+        let (_, exprs) = parse(input).unwrap();
+        let texts: Vec<String> = exprs.iter().map(|expr| match expr {
+            Expr::Instruction(inst) => inst.to_string(),
+            Expr::Label(label) => label.to_string(),
+        }).collect();
+        assert_eq!(texts, vec![
+            "afterfib-n-1",
+            "(assign n (reg val))",
+            "(assign val2 (op +) (reg val) (reg n2))",
+        ]);
+    }
+
+    #[test]
+    fn test_parse_rejects_malformed_instructions() {
+        let message = |input: &str| parse(input).unwrap_err().message;
+        assert_eq!(message("(save a b)"), "save expression expects a register name like (save name)");
+        assert_eq!(message("(test (op =) (op rem))"), "Nested op is not allowed!");
+        assert_eq!(message("(goto (const 3))"), "The destination of goto should be a label or a register");
+        assert_eq!(message("(assign a (reg b) (reg c))"), "Invalid value expression: only an operation takes operands");
+        assert_eq!(message("42"), "Expected identifier for label");
+    }
+
+    #[test]
+    fn test_spans_of_labels_and_instructions() {
+        let input = "gcd\n  (test (op =) (reg b) (const 0))\n  (branch (label gcd-done))";
+        let (_, exprs) = parse(input).unwrap();
+        let spans: Vec<(usize, usize, usize, usize)> = exprs.iter()
+            .map(|expr| expr.span())
+            .map(|span| (span.start, span.end, span.line, span.column))
+            .collect();
+        assert_eq!(spans, vec![(0, 3, 1, 1), (6, 37, 2, 3), (40, 65, 3, 3)]);
+        let Expr::Instruction(branch) = &exprs[2] else { panic!("expected an instruction") };
+        let super::InstructionKind::Branch(label) = branch.kind() else { panic!("expected a branch") };
+        assert_eq!(&input[label.span().start..label.span().end], "gcd-done");
+    }
+
+    #[test]
+    fn test_instruction_text_round_trips() {
+        let input = "(assign t (op rem) (reg a) (reg b))
+            (test (op =) (reg b) (const 0))
+            (branch (label gcd-done))
+            (goto (reg continue))
+            (save n)
+            (restore n)
+            (perform (op print) (reg a))
+            (assign continue (label after))";
+
+        let (_, exprs) = parse(input).unwrap();
+        let texts: Vec<String> = exprs.iter().map(|expr| match expr {
+            Expr::Instruction(inst) => inst.to_string(),
+            Expr::Label(label) => label.to_string(),
+        }).collect();
+        let expected: Vec<&str> = input.lines().map(str::trim).collect();
+        assert_eq!(texts, expected);
+    }
+
+    #[test]
+    fn test_parse_definitions() {
+        let input = format!("; two machines in one file
+          (define-machine gcd
+            (registers a b t)
+            (operations rem =)
+            (controller {GCD}))
+          (define-machine idle (controller))");
+
+        let definitions = parse_definitions(&input).unwrap();
+        assert_eq!(definitions.len(), 2);
+        let gcd = &definitions[0];
+        assert_eq!(gcd.name, "gcd");
+        assert_eq!(gcd.registers, vec!["a", "b", "t"]);
+        assert_eq!(gcd.operations, vec!["rem", "="]);
+        assert_eq!(gcd.controller.len(), 8);
+        assert_eq!((gcd.span.line, gcd.span.column), (2, 11));
+        assert_eq!(definitions[1].name, "idle");
+        assert!(definitions[1].registers.is_empty() && definitions[1].controller.is_empty());
+    }
+
+    #[test]
+    fn test_parse_definitions_rejects_malformed_machines() {
+        let message = |input: &str| parse_definitions(input).unwrap_err().message;
+        assert_eq!(message("(define-machine m (registers a))"), "Machine m has no (controller ...) section");
+        assert_eq!(message("(define-machine m (stack a) (controller))"), "Unknown section stack in machine m");
+        assert_eq!(message("(define-machine m (controller) (controller))"), "Duplicate controller section in machine m");
+        assert_eq!(message("(define-machine m (registers 1) (controller))"), "Expects a name");
+        assert_eq!(message("(define-machine m (controller (jump a)))"), "Failed to parse instruction: jump");
+        assert!(message("gcd (test (op =) (reg b) (const 0))").starts_with("Expects a machine definition"));
+    }
 }
@@ -0,0 +1,172 @@
+use crate::machine::diagnostic::Span;
+use crate::machine::error::ParseError;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TokenKind {
+    LeftParen,
+    RightParen,
+    Symbol(String),
+    Integer(i64),
+    Str(String),
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Token {
+    pub kind: TokenKind,
+    pub span: Span,
+}
+
+// Split controller text into tokens. Whitespace and `;` comments up to the end
+// of the line are skipped. Any run of characters that is not whitespace, a
+// paren, a '"' or a ';' is an atom: an integer if it reads as one, else a symbol.
+pub fn tokenize(source: &str) -> Result<Vec<Token>, ParseError> {
+    Lexer { source, position: 0, line: 1, column: 1 }.tokenize()
+}
+
+// Line and column are advanced along with the byte position, so building a
+// span never has to rescan the source.
+struct Lexer<'a> {
+    source: &'a str,
+    position: usize,
+    line: usize,
+    column: usize,
+}
+
+impl Lexer<'_> {
+    fn peek(&self) -> Option<char> {
+        self.source[self.position..].chars().next()
+    }
+    fn bump(&mut self) -> Option<char> {
+        let c = self.peek()?;
+        self.position += c.len_utf8();
+        if c == '\n' {
+            self.line += 1;
+            self.column = 1;
+        } else {
+            self.column += 1;
+        }
+        Some(c)
+    }
+    fn here(&self) -> Span {
+        Span { start: self.position, end: self.position, line: self.line, column: self.column }
+    }
+
+    fn tokenize(mut self) -> Result<Vec<Token>, ParseError> {
+        let mut tokens = Vec::new();
+        while let Some(c) = self.peek() {
+            let mut span = self.here();
+            let kind = match c {
+                c if c.is_whitespace() => {
+                    self.bump();
+                    continue;
+                }
+                ';' => {
+                    while self.peek().is_some_and(|c| c != '\n') {
+                        self.bump();
+                    }
+                    continue;
+                }
+                '(' => {
+                    self.bump();
+                    TokenKind::LeftParen
+                }
+                ')' => {
+                    self.bump();
+                    TokenKind::RightParen
+                }
+                '"' => self.string(span)?,
+                _ => self.atom(span)?,
+            };
+            span.end = self.position;
+            tokens.push(Token { kind, span });
+        }
+        Ok(tokens)
+    }
+
+    fn string(&mut self, start: Span) -> Result<TokenKind, ParseError> {
+        self.bump();
+        let mut text = String::new();
+        loop {
+            let escape = self.here();
+            match self.bump() {
+                None => {
+                    return Err(ParseError { span: start, message: "Unterminated string literal".to_string() });
+                }
+                Some('"') => return Ok(TokenKind::Str(text)),
+                Some('\\') => match self.bump() {
+                    Some('"') => text.push('"'),
+                    Some('\\') => text.push('\\'),
+                    Some('n') => text.push('\n'),
+                    Some('t') => text.push('\t'),
+                    other => {
+                        let span = Span { end: self.position, ..escape };
+                        let escaped = other.map(String::from).unwrap_or_default();
+                        return Err(ParseError { span, message: format!("Unknown escape sequence '\\{escaped}' in string") });
+                    }
+                },
+                Some(c) => text.push(c),
+            }
+        }
+    }
+
+    fn atom(&mut self, mut span: Span) -> Result<TokenKind, ParseError> {
+        while self.peek().is_some_and(|c| !(c.is_whitespace() || matches!(c, '(' | ')' | '"' | ';'))) {
+            self.bump();
+        }
+        span.end = self.position;
+        let text = &self.source[span.start..span.end];
+
+        let digits = text.strip_prefix(['+', '-']).unwrap_or(text);
+        if !digits.is_empty() && digits.chars().all(|c| c.is_ascii_digit()) {
+            text.parse()
+                .map(TokenKind::Integer)
+                .map_err(|e| ParseError { span, message: format!("Failed to parse the value '{text}' : {e}") })
+        } else {
+            Ok(TokenKind::Symbol(text.to_string()))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{tokenize, TokenKind};
+
+    #[test]
+    fn test_tokenize_atoms_strings_and_comments() {
+        let kinds: Vec<TokenKind> = tokenize("(assign n-1 (const -42)) ; decrement\n(const \"a \\\"b\\\"\")")
+            .unwrap()
+            .into_iter()
+            .map(|token| token.kind)
+            .collect();
+        assert_eq!(kinds, vec![
+            TokenKind::LeftParen,
+            TokenKind::Symbol("assign".to_string()),
+            TokenKind::Symbol("n-1".to_string()),
+            TokenKind::LeftParen,
+            TokenKind::Symbol("const".to_string()),
+            TokenKind::Integer(-42),
+            TokenKind::RightParen,
+            TokenKind::RightParen,
+            TokenKind::LeftParen,
+            TokenKind::Symbol("const".to_string()),
+            TokenKind::Str("a \"b\"".to_string()),
+            TokenKind::RightParen,
+        ]);
+    }
+
+    #[test]
+    fn test_tokenize_tracks_lines_and_columns() {
+        let tokens = tokenize("gcd\n  (test)").unwrap();
+        let positions: Vec<(usize, usize, usize, usize)> = tokens.iter()
+            .map(|token| (token.span.start, token.span.end, token.span.line, token.span.column))
+            .collect();
+        assert_eq!(positions, vec![(0, 3, 1, 1), (6, 7, 2, 3), (7, 11, 2, 4), (11, 12, 2, 8)]);
+    }
+
+    #[test]
+    fn test_tokenize_unterminated_string() {
+        let error = tokenize("(const \"abc)").unwrap_err();
+        assert_eq!(error.message, "Unterminated string literal");
+        assert_eq!(error.span.column, 8);
+    }
+}
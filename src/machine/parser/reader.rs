@@ -0,0 +1,120 @@
+use std::fmt;
+
+use super::lexer::{tokenize, Token, TokenKind};
+use crate::machine::diagnostic::Span;
+use crate::machine::error::ParseError;
+
+// A generic s-expression, knowing nothing about register machines.
+// The span of a list covers everything from its '(' to its ')'.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct SExpr {
+    pub kind: SExprKind,
+    pub span: Span,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum SExprKind {
+    Symbol(String),
+    Integer(i64),
+    Str(String),
+    List(Vec<SExpr>),
+}
+
+impl SExpr {
+    pub fn as_symbol(&self) -> Option<&str> {
+        match &self.kind {
+            SExprKind::Symbol(name) => Some(name),
+            _ => None,
+        }
+    }
+    pub fn as_list(&self) -> Option<&[SExpr]> {
+        match &self.kind {
+            SExprKind::List(items) => Some(items),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for SExpr {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match &self.kind {
+            SExprKind::Symbol(name) => write!(f, "{name}"),
+            SExprKind::Integer(value) => write!(f, "{value}"),
+            SExprKind::Str(text) => write!(f, "{text:?}"),
+            SExprKind::List(items) => {
+                write!(f, "(")?;
+                for (i, item) in items.iter().enumerate() {
+                    if i > 0 {
+                        write!(f, " ")?;
+                    }
+                    write!(f, "{item}")?;
+                }
+                write!(f, ")")
+            }
+        }
+    }
+}
+
+// Read every top-level s-expression of `source`.
+pub fn read(source: &str) -> Result<Vec<SExpr>, ParseError> {
+    let mut tokens = tokenize(source)?.into_iter();
+    let mut exprs = Vec::new();
+    while let Some(token) = tokens.next() {
+        exprs.push(read_expr(token, &mut tokens)?);
+    }
+    Ok(exprs)
+}
+
+fn read_expr(token: Token, tokens: &mut impl Iterator<Item = Token>) -> Result<SExpr, ParseError> {
+    let kind = match token.kind {
+        TokenKind::Symbol(name) => SExprKind::Symbol(name),
+        TokenKind::Integer(value) => SExprKind::Integer(value),
+        TokenKind::Str(text) => SExprKind::Str(text),
+        TokenKind::RightParen => {
+            return Err(ParseError { span: token.span, message: "Unexpected ')'".to_string() });
+        }
+        TokenKind::LeftParen => {
+            let mut items = Vec::new();
+            loop {
+                match tokens.next() {
+                    None => {
+                        return Err(ParseError { span: token.span, message: "Expects a ')' to close this '('".to_string() });
+                    }
+                    Some(Token { kind: TokenKind::RightParen, span }) => {
+                        let span = Span { end: span.end, ..token.span };
+                        return Ok(SExpr { kind: SExprKind::List(items), span });
+                    }
+                    Some(next) => items.push(read_expr(next, tokens)?),
+                }
+            }
+        }
+    };
+    Ok(SExpr { kind, span: token.span })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{read, SExprKind};
+
+    #[test]
+    fn test_read_nested_lists() {
+        let exprs = read("here (assign a (op +) (reg a) (const 1)) ; comment\n(perform (op print) (const \"done\"))").unwrap();
+        assert_eq!(exprs.len(), 3);
+        assert_eq!(exprs[0].kind, SExprKind::Symbol("here".to_string()));
+        assert_eq!(exprs[1].to_string(), "(assign a (op +) (reg a) (const 1))");
+        assert_eq!((exprs[1].span.start, exprs[1].span.end), (5, 40));
+        assert_eq!(exprs[2].to_string(), "(perform (op print) (const \"done\"))");
+        assert_eq!(exprs[2].span.line, 2);
+    }
+
+    #[test]
+    fn test_read_unbalanced_parens() {
+        let unclosed = read("(assign a\n  (reg b)").unwrap_err();
+        assert_eq!(unclosed.message, "Expects a ')' to close this '('");
+        assert_eq!((unclosed.span.line, unclosed.span.column), (1, 1));
+
+        let stray = read("(save a))").unwrap_err();
+        assert_eq!(stray.message, "Unexpected ')'");
+        assert_eq!(stray.span.start, 8);
+    }
+}
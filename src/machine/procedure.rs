@@ -1,26 +1,93 @@
-
 use super::Machine;
+use super::error::{AssembleError, RuntimeErrorKind};
+use super::parser::Instruction;
+use super::value::{FromValue, IntoValues, OperandKind, Value};
+use std::fmt;
+use std::marker::PhantomData;
+use std::rc::Rc;
 
 pub trait Executor: CloneExecutor {
     type Oprands;
 
-    fn execute(&self, machine: &mut Machine, oprands: Self::Oprands) -> Vec<u32>;
+    fn execute(&self, machine: &mut Machine, oprands: Self::Oprands) -> Result<Vec<Value>, RuntimeErrorKind>;
+
+    // How many operands of which kinds the operation takes, checked when assembling.
+    // By default any number of operands, none of them a label.
+    fn signature(&self) -> Signature {
+        Signature::new(Arity::Variadic(0))
+    }
+}
+
+// The number of operands an operation takes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Arity {
+    Fixed(usize),
+    // At least this many.
+    Variadic(usize),
+    // From min to max, both included.
+    Range(usize, usize),
+}
+
+impl Arity {
+    pub fn accepts(&self, count: usize) -> bool {
+        match *self {
+            Arity::Fixed(n) => count == n,
+            Arity::Variadic(min) => count >= min,
+            Arity::Range(min, max) => (min..=max).contains(&count),
+        }
+    }
+}
+
+impl fmt::Display for Arity {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Arity::Fixed(n) => write!(f, "{n}"),
+            Arity::Variadic(min) => write!(f, "at least {min}"),
+            Arity::Range(min, max) => write!(f, "{min} to {max}"),
+        }
+    }
+}
+
+// The arity of an operation and the kind of each operand. Operands past the
+// end of `operands` have the kind of the last one, so a variadic operation
+// can declare them all at once; with no kinds at all every operand is Any.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Signature {
+    pub arity: Arity,
+    pub operands: Vec<OperandKind>,
+}
+
+impl Signature {
+    pub fn new(arity: Arity) -> Self {
+        Signature { arity, operands: Vec::new() }
+    }
+    pub fn with_operands(mut self, operands: &[OperandKind]) -> Self {
+        self.operands = operands.to_vec();
+        self
+    }
+    pub fn operand(&self, position: usize) -> OperandKind {
+        self.operands
+            .get(position)
+            .or(self.operands.last())
+            .copied()
+            .unwrap_or(OperandKind::Any)
+    }
 }
 
 pub trait CloneExecutor {
-    fn clone_box(&self) -> Box<dyn Executor<Oprands = Vec<u32>>>;
+    fn clone_box(&self) -> Box<dyn Executor<Oprands = Vec<Value>>>;
 }
 
 impl<T> CloneExecutor for T
 where
-    T: 'static + Executor<Oprands = Vec<u32>> + Clone,
+    T: 'static + Executor<Oprands = Vec<Value>> + Clone,
 {
-    fn clone_box(&self) -> Box<dyn Executor<Oprands = Vec<u32>>> {
+    fn clone_box(&self) -> Box<dyn Executor<Oprands = Vec<Value>>> {
         Box::new(self.clone())
     }
 }
 
-pub type Operation = Box<dyn Executor<Oprands = Vec<u32>>>;
+pub type Operation = Box<dyn Executor<Oprands = Vec<Value>>>;
 
 impl Clone for Operation {
     fn clone(&self) -> Self {
@@ -28,11 +95,109 @@ impl Clone for Operation {
     }
 }
 
-pub type Procedure = Box<dyn Fn(&mut Machine)>;
-pub type ValueProcedure = Box<dyn Fn(&mut Machine) -> Vec<u32>>;
+// Anything that can be installed as an operation: an Operation itself, or a plain
+// Rust function or closure whose arguments implement FromValue and whose result
+// implements IntoValues. `Args` only tells the implementations apart.
+pub trait IntoOperation<Args> {
+    fn into_operation(self) -> Operation;
+}
+
+pub struct OperationMarker;
+
+impl IntoOperation<OperationMarker> for Operation {
+    fn into_operation(self) -> Operation {
+        self
+    }
+}
+
+// A name and an operation ready for make_machine, e.g. `op("rem", |a: i64, b: i64| a % b)`.
+pub fn op<Args>(name: &str, operation: impl IntoOperation<Args>) -> (String, Operation) {
+    (name.to_string(), operation.into_operation())
+}
+
+// Wraps a Rust function; `Signature` is the fn pointer type of its signature.
+struct FnOperation<F, Signature> {
+    f: F,
+    signature: PhantomData<Signature>,
+}
+
+impl<F: Clone, Signature> Clone for FnOperation<F, Signature> {
+    fn clone(&self) -> Self {
+        FnOperation { f: self.f.clone(), signature: PhantomData }
+    }
+}
+
+macro_rules! impl_into_operation {
+    ($arity:literal; $($arg:ident $value:ident),*) => {
+        impl<F, R, $($arg,)*> IntoOperation<fn($($arg,)*) -> R> for F
+        where
+            F: Fn($($arg),*) -> R + Clone + 'static,
+            R: IntoValues + 'static,
+            $($arg: FromValue + 'static,)*
+        {
+            fn into_operation(self) -> Operation {
+                Box::new(FnOperation::<F, fn($($arg,)*) -> R> { f: self, signature: PhantomData })
+            }
+        }
+
+        impl<F, R, $($arg,)*> Executor for FnOperation<F, fn($($arg,)*) -> R>
+        where
+            F: Fn($($arg),*) -> R + Clone + 'static,
+            R: IntoValues + 'static,
+            $($arg: FromValue + 'static,)*
+        {
+            type Oprands = Vec<Value>;
+
+            fn execute(&self, _machine: &mut Machine, oprands: Vec<Value>) -> Result<Vec<Value>, RuntimeErrorKind> {
+                let found = oprands.len();
+                let [$($value),*]: [Value; $arity] = oprands
+                    .try_into()
+                    .map_err(|_| RuntimeErrorKind::OperandCount { expected: $arity, found })?;
+                (self.f)($($arg::from_value($value)?),*).into_values()
+            }
+
+            fn signature(&self) -> Signature {
+                Signature::new(Arity::Fixed($arity)).with_operands(&[$($arg::KIND),*])
+            }
+        }
+    };
+}
+
+impl_into_operation!(0;);
+impl_into_operation!(1; A a);
+impl_into_operation!(2; A a, B b);
+impl_into_operation!(3; A a, B b, C c);
+impl_into_operation!(4; A a, B b, C c, D d);
+impl_into_operation!(5; A a, B b, C c, D d, E e);
+
+// What the run loop should do with the pc once an instruction has been executed.
+// Only goto and a taken branch jump, every other instruction advances.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Next {
+    Advance,
+    Jump(usize),
+}
+
+// Rc rather than Box: the run loop clones the procedure out of the machine
+// before handing the machine itself to it.
+pub type Procedure = Rc<dyn Fn(&mut Machine) -> Result<Next, RuntimeErrorKind>>;
+pub type ValueProcedure = Box<dyn Fn(&mut Machine) -> Result<Vec<Value>, RuntimeErrorKind>>;
+
+// An assembled instruction keeps its text next to the execution procedure,
+// like the (text . proc) pairs of SICP, so errors can show what was running.
+// `label` is the label most recently defined before the instruction, if any.
+pub struct MachineInstruction {
+    pub text: Instruction,
+    pub label: Option<String>,
+    pub proc: Procedure,
+}
 
-pub fn combine_procedures(procedures: Vec<ValueProcedure>) -> Result<ValueProcedure, String> {
+pub fn combine_procedures(procedures: Vec<ValueProcedure>) -> Result<ValueProcedure, AssembleError> {
     Ok(Box::new(move |machine: &mut Machine| {
-        procedures.iter().flat_map(|proc| proc(machine)).collect()
+        let mut values = Vec::new();
+        for proc in procedures.iter() {
+            values.extend(proc(machine)?);
+        }
+        Ok(values)
     }))
 }
@@ -0,0 +1,221 @@
+use std::cell::RefCell;
+use std::fmt;
+use std::io::{self, Stderr, Write};
+use std::rc::Rc;
+
+use super::parser::Instruction;
+use super::value::Value;
+
+// Something that happened while the machine was running with tracing on.
+#[derive(Debug, Clone, Copy)]
+pub enum TraceEvent<'a> {
+    // An instruction about to be executed, with the label it comes after (ex 5.16, 5.17).
+    Instruction { pc: usize, label: Option<&'a str>, instruction: &'a Instruction },
+    // A write to a traced register (ex 5.18); `old` is None if it was never assigned.
+    Register { pc: usize, name: &'a str, old: Option<&'a Value>, new: &'a Value },
+}
+
+impl fmt::Display for TraceEvent<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TraceEvent::Instruction { label: Some(label), instruction, .. } => write!(f, "{label}: {instruction}"),
+            TraceEvent::Instruction { label: None, instruction, .. } => write!(f, "{instruction}"),
+            TraceEvent::Register { pc, name, old: Some(old), new } => write!(f, "{name}: {old} -> {new} at pc {pc}"),
+            TraceEvent::Register { pc, name, old: None, new } => write!(f, "{name}: unassigned -> {new} at pc {pc}"),
+        }
+    }
+}
+
+// Where trace events go.
+pub trait TraceSink {
+    fn trace(&mut self, event: &TraceEvent);
+}
+
+// Writes each event as a line, e.g. to stderr or a file. Write errors are ignored:
+// a broken trace shouldn't stop the machine.
+pub struct WriteSink<W: Write>(pub W);
+
+impl WriteSink<Stderr> {
+    pub fn stderr() -> Self {
+        WriteSink(io::stderr())
+    }
+}
+
+impl<W: Write> TraceSink for WriteSink<W> {
+    fn trace(&mut self, event: &TraceEvent) {
+        let _ = writeln!(self.0, "{event}");
+    }
+}
+
+// Keeps the events as lines in memory. Clones share the lines, so keep one
+// clone to read them after handing the other to the machine.
+#[derive(Debug, Clone, Default)]
+pub struct MemorySink(Rc<RefCell<Vec<String>>>);
+
+impl MemorySink {
+    pub fn lines(&self) -> Vec<String> {
+        self.0.borrow().clone()
+    }
+    pub fn clear(&self) {
+        self.0.borrow_mut().clear();
+    }
+    // The lines so far, leaving the sink empty.
+    pub fn take(&self) -> Vec<String> {
+        self.0.take()
+    }
+}
+
+impl TraceSink for MemorySink {
+    fn trace(&mut self, event: &TraceEvent) {
+        self.0.borrow_mut().push(event.to_string());
+    }
+}
+
+// Keeps what the machine prints in memory, to hand to Machine::set_output. Like
+// MemorySink, clones share the text.
+#[derive(Debug, Clone, Default)]
+pub struct MemoryOutput(Rc<RefCell<Vec<u8>>>);
+
+impl MemoryOutput {
+    // The text so far, leaving the output empty.
+    pub fn take(&self) -> String {
+        String::from_utf8_lossy(&self.0.take()).into_owned()
+    }
+}
+
+impl Write for MemoryOutput {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.borrow_mut().write(buf)
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{MemorySink, TraceEvent, TraceSink, WriteSink};
+    use crate::machine::operations::standard_operations;
+    use crate::machine::parser::{parse, Expr};
+    use crate::machine::{Halted, Machine};
+
+    const COUNTDOWN: &str = "
+        (assign n (const 2))
+      loop
+        (test (op =) (reg n) (const 0))
+        (branch (label done))
+        (assign n (op -) (reg n) (const 1))
+        (goto (label loop))
+      done";
+
+    #[test]
+    fn test_trace_instructions_with_labels() {
+        let mut machine = Machine::make_machine(&["n"], standard_operations(), COUNTDOWN).unwrap();
+        let sink = MemorySink::default();
+        machine.set_trace_sink(Box::new(sink.clone()));
+        machine.trace_on();
+        assert_eq!(machine.start(), Ok(Halted::Done));
+        let lines = sink.lines();
+        assert_eq!(lines.len(), 11);
+        assert_eq!(lines[..5], [
+            "(assign n (const 2))",
+            "loop: (test (op =) (reg n) (const 0))",
+            "loop: (branch (label done))",
+            "loop: (assign n (op -) (reg n) (const 1))",
+            "loop: (goto (label loop))",
+        ]);
+
+        sink.clear();
+        machine.trace_off();
+        assert_eq!(machine.start(), Ok(Halted::Done));
+        assert!(sink.lines().is_empty());
+    }
+
+    #[test]
+    fn test_trace_from_the_controller_to_a_writer() {
+        let controller = "(perform (op trace-on)) (assign n (const 1)) (perform (op trace-off)) (assign n (const 2))";
+        let mut machine = Machine::make_machine(&["n"], standard_operations(), controller).unwrap();
+        let sink = MemorySink::default();
+        machine.set_trace_sink(Box::new(sink.clone()));
+        assert_eq!(machine.start(), Ok(Halted::Done));
+        assert_eq!(sink.lines(), vec!["(assign n (const 1))", "(perform (op trace-off))"]);
+
+    }
+
+    #[test]
+    fn test_write_sink_writes_lines() {
+        let (_, text) = parse("(save n)").unwrap();
+        let Expr::Instruction(instruction) = &text[0] else { unreachable!() };
+        let mut sink = WriteSink(Vec::new());
+        sink.trace(&TraceEvent::Instruction { pc: 0, label: Some("loop"), instruction });
+        sink.trace(&TraceEvent::Instruction { pc: 0, label: None, instruction });
+        assert_eq!(String::from_utf8(sink.0).unwrap(), "loop: (save n)\n(save n)\n");
+    }
+
+    #[test]
+    fn test_trace_register_writes() {
+        let mut machine = Machine::make_machine(&["n"], standard_operations(), COUNTDOWN).unwrap();
+        let sink = MemorySink::default();
+        machine.set_trace_sink(Box::new(sink.clone()));
+        machine.trace_register_on("n").unwrap();
+        assert_eq!(machine.start(), Ok(Halted::Done));
+        assert_eq!(sink.lines(), vec![
+            "n: unassigned -> 2 at pc 0",
+            "n: 2 -> 1 at pc 3",
+            "n: 1 -> 0 at pc 3",
+        ]);
+
+        sink.clear();
+        machine.set_register_contents("n", 5).unwrap();
+        machine.trace_register_off("n").unwrap();
+        machine.set_register_contents("n", 6).unwrap();
+        assert_eq!(sink.lines(), vec!["n: 0 -> 5 at pc 5"]);
+        assert!(machine.trace_register_on("m").is_err());
+    }
+
+    #[test]
+    fn test_trace_the_pc() {
+        let mut machine = Machine::make_machine(&["a"], standard_operations(), "(assign a (const 1)) (goto (label end)) end").unwrap();
+        let sink = MemorySink::default();
+        machine.set_trace_sink(Box::new(sink.clone()));
+        machine.trace_register_on("pc").unwrap();
+        assert_eq!(machine.start(), Ok(Halted::Done));
+        assert_eq!(sink.lines(), vec![
+            "pc: unassigned -> #<label 0> at pc 0",
+            "pc: #<label 0> -> #<label 1> at pc 0",
+            "pc: #<label 1> -> #<label 2> at pc 1",
+        ]);
+
+        // A write the pc refuses is never traced, and tracing it doesn't get in the way.
+        let cases = [
+            ("(assign pc (const 2))", vec!["pc: unassigned -> #<label 0> at pc 0"]),
+            ("(save a) (restore pc)", vec!["pc: unassigned -> #<label 0> at pc 0", "pc: #<label 0> -> #<label 1> at pc 0"]),
+        ];
+        for (controller, expected) in cases {
+            let mut machine = Machine::make_machine(&["a"], standard_operations(), controller).unwrap();
+            let sink = MemorySink::default();
+            machine.set_trace_sink(Box::new(sink.clone()));
+            machine.set_register_contents("a", 2).unwrap();
+            machine.trace_register_on("pc").unwrap();
+            assert!(machine.start().is_err());
+            assert_eq!(sink.lines(), expected);
+        }
+    }
+
+    #[test]
+    fn test_trace_register_from_the_controller() {
+        let controller = "
+            (assign a (const 1))
+            (perform (op trace-register-on) (const a))
+            (save a)
+            (assign a (const 2))
+            (restore a)
+            (perform (op trace-register-off) (const a))
+            (assign a (const 3))";
+        let mut machine = Machine::make_machine(&["a"], standard_operations(), controller).unwrap();
+        let sink = MemorySink::default();
+        machine.set_trace_sink(Box::new(sink.clone()));
+        assert_eq!(machine.start(), Ok(Halted::Done));
+        assert_eq!(sink.lines(), vec!["a: 1 -> 2 at pc 3", "a: 2 -> 1 at pc 4"]);
+    }
+}
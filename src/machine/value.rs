@@ -0,0 +1,247 @@
+use std::fmt;
+
+use super::error::RuntimeErrorKind;
+
+// Everything a register, the stack or an operation can hold.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Value {
+    Integer(i64),
+    Boolean(bool),
+    Symbol(String),
+    Str(String),
+    // An instruction pointer: the index of an instruction, e.g. from (label name) or the pc.
+    Label(usize),
+    // A pointer into list-structured memory.
+    Pair(usize),
+    // A primitive procedure, known by its name.
+    Primitive(String),
+}
+
+// The kind of a Value, used to report an operand of the wrong kind.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum ValueKind {
+    Integer,
+    Boolean,
+    Symbol,
+    Str,
+    Label,
+    Pair,
+    Primitive,
+}
+
+// What an operation accepts as one of its operands, checked against constant
+// and label operands when assembling. `Any` takes every value except a label:
+// like in SICP exercise 5.9, operations work on data, never on labels.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum OperandKind {
+    Any,
+    Kind(ValueKind),
+}
+
+impl OperandKind {
+    pub fn accepts(&self, kind: ValueKind) -> bool {
+        match self {
+            OperandKind::Any => kind != ValueKind::Label,
+            OperandKind::Kind(expected) => *expected == kind,
+        }
+    }
+}
+
+impl Value {
+    pub fn kind(&self) -> ValueKind {
+        match self {
+            Value::Integer(_) => ValueKind::Integer,
+            Value::Boolean(_) => ValueKind::Boolean,
+            Value::Symbol(_) => ValueKind::Symbol,
+            Value::Str(_) => ValueKind::Str,
+            Value::Label(_) => ValueKind::Label,
+            Value::Pair(_) => ValueKind::Pair,
+            Value::Primitive(_) => ValueKind::Primitive,
+        }
+    }
+    // As in Scheme, every value except false counts as true, which is what branch tests.
+    pub fn is_true(&self) -> bool {
+        !matches!(self, Value::Boolean(false))
+    }
+
+    fn mismatch(&self, expected: ValueKind) -> RuntimeErrorKind {
+        RuntimeErrorKind::TypeMismatch { expected, found: self.clone() }
+    }
+    pub fn as_integer(&self) -> Result<i64, RuntimeErrorKind> {
+        match self {
+            Value::Integer(value) => Ok(*value),
+            _ => Err(self.mismatch(ValueKind::Integer)),
+        }
+    }
+    pub fn as_boolean(&self) -> Result<bool, RuntimeErrorKind> {
+        match self {
+            Value::Boolean(value) => Ok(*value),
+            _ => Err(self.mismatch(ValueKind::Boolean)),
+        }
+    }
+    pub fn as_symbol(&self) -> Result<&str, RuntimeErrorKind> {
+        match self {
+            Value::Symbol(name) => Ok(name),
+            _ => Err(self.mismatch(ValueKind::Symbol)),
+        }
+    }
+    pub fn as_str(&self) -> Result<&str, RuntimeErrorKind> {
+        match self {
+            Value::Str(text) => Ok(text),
+            _ => Err(self.mismatch(ValueKind::Str)),
+        }
+    }
+    pub fn as_label(&self) -> Result<usize, RuntimeErrorKind> {
+        match self {
+            Value::Label(index) => Ok(*index),
+            _ => Err(self.mismatch(ValueKind::Label)),
+        }
+    }
+    pub fn as_pair(&self) -> Result<usize, RuntimeErrorKind> {
+        match self {
+            Value::Pair(index) => Ok(*index),
+            _ => Err(self.mismatch(ValueKind::Pair)),
+        }
+    }
+}
+
+impl From<i64> for Value {
+    fn from(value: i64) -> Self {
+        Value::Integer(value)
+    }
+}
+impl From<bool> for Value {
+    fn from(value: bool) -> Self {
+        Value::Boolean(value)
+    }
+}
+
+impl fmt::Display for Value {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Value::Integer(value) => write!(f, "{value}"),
+            Value::Boolean(true) => write!(f, "#t"),
+            Value::Boolean(false) => write!(f, "#f"),
+            Value::Symbol(name) => write!(f, "{name}"),
+            Value::Str(text) => write!(f, "{text:?}"),
+            Value::Label(index) => write!(f, "#<label {index}>"),
+            Value::Pair(index) => write!(f, "#<pair {index}>"),
+            Value::Primitive(name) => write!(f, "#<primitive {name}>"),
+        }
+    }
+}
+
+impl fmt::Display for OperandKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            OperandKind::Any => write!(f, "any value but a label"),
+            OperandKind::Kind(kind) => write!(f, "{kind}"),
+        }
+    }
+}
+
+impl fmt::Display for ValueKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let name = match self {
+            ValueKind::Integer => "integer",
+            ValueKind::Boolean => "boolean",
+            ValueKind::Symbol => "symbol",
+            ValueKind::Str => "string",
+            ValueKind::Label => "label",
+            ValueKind::Pair => "pair",
+            ValueKind::Primitive => "primitive procedure",
+        };
+        write!(f, "{name}")
+    }
+}
+
+// An operand of an operation registered from a plain Rust function.
+pub trait FromValue: Sized {
+    // The operand kind declared for an argument of this type.
+    const KIND: OperandKind = OperandKind::Any;
+
+    fn from_value(value: Value) -> Result<Self, RuntimeErrorKind>;
+}
+
+impl FromValue for Value {
+    fn from_value(value: Value) -> Result<Self, RuntimeErrorKind> {
+        Ok(value)
+    }
+}
+impl FromValue for i64 {
+    const KIND: OperandKind = OperandKind::Kind(ValueKind::Integer);
+
+    fn from_value(value: Value) -> Result<Self, RuntimeErrorKind> {
+        value.as_integer()
+    }
+}
+impl FromValue for u32 {
+    const KIND: OperandKind = OperandKind::Kind(ValueKind::Integer);
+
+    fn from_value(value: Value) -> Result<Self, RuntimeErrorKind> {
+        u32::try_from(value.as_integer()?).map_err(|_| RuntimeErrorKind::Overflow)
+    }
+}
+impl FromValue for bool {
+    const KIND: OperandKind = OperandKind::Kind(ValueKind::Boolean);
+
+    fn from_value(value: Value) -> Result<Self, RuntimeErrorKind> {
+        value.as_boolean()
+    }
+}
+impl FromValue for String {
+    const KIND: OperandKind = OperandKind::Kind(ValueKind::Str);
+
+    fn from_value(value: Value) -> Result<Self, RuntimeErrorKind> {
+        match value {
+            Value::Str(text) => Ok(text),
+            _ => Err(value.mismatch(ValueKind::Str)),
+        }
+    }
+}
+
+// The result of an operation registered from a plain Rust function.
+// `()` produces no value, so such a function only fits `perform`.
+pub trait IntoValues {
+    fn into_values(self) -> Result<Vec<Value>, RuntimeErrorKind>;
+}
+
+impl IntoValues for () {
+    fn into_values(self) -> Result<Vec<Value>, RuntimeErrorKind> {
+        Ok(Vec::new())
+    }
+}
+impl IntoValues for Value {
+    fn into_values(self) -> Result<Vec<Value>, RuntimeErrorKind> {
+        Ok(vec![self])
+    }
+}
+impl IntoValues for i64 {
+    fn into_values(self) -> Result<Vec<Value>, RuntimeErrorKind> {
+        Ok(vec![Value::Integer(self)])
+    }
+}
+impl IntoValues for u32 {
+    fn into_values(self) -> Result<Vec<Value>, RuntimeErrorKind> {
+        Ok(vec![Value::Integer(self.into())])
+    }
+}
+impl IntoValues for bool {
+    fn into_values(self) -> Result<Vec<Value>, RuntimeErrorKind> {
+        Ok(vec![Value::Boolean(self)])
+    }
+}
+impl IntoValues for String {
+    fn into_values(self) -> Result<Vec<Value>, RuntimeErrorKind> {
+        Ok(vec![Value::Str(self)])
+    }
+}
+impl<T, E> IntoValues for Result<T, E>
+where
+    T: IntoValues,
+    E: Into<RuntimeErrorKind>,
+{
+    fn into_values(self) -> Result<Vec<Value>, RuntimeErrorKind> {
+        self.map_err(Into::into)?.into_values()
+    }
+}
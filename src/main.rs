@@ -1,3 +1,59 @@
-fn main() {
-    println!("Hello, world!");
+use std::io;
+use std::process::ExitCode;
+
+use sicp_5_2::cli::{self, Command};
+use sicp_5_2::debugger::Debugger;
+use sicp_5_2::machine::operations::standard_operations;
+use sicp_5_2::machine::{Machine, MachineOptions};
+
+fn main() -> ExitCode {
+    let args: Vec<String> = std::env::args().skip(1).collect();
+    let command = match cli::parse_args(&args) {
+        Ok(command) => command,
+        Err(message) => {
+            eprintln!("{message}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let path = match &command {
+        Command::Run(options) => &options.path,
+        Command::Debug { path } => path,
+    };
+    let source = match std::fs::read_to_string(path) {
+        Ok(source) => source,
+        Err(e) => {
+            eprintln!("error: cannot read {path}: {e}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let succeeded = match command {
+        Command::Run(options) => cli::run(&source, &options, &mut io::stdout(), &mut io::stderr()),
+        Command::Debug { .. } => debug(&source),
+    };
+    match succeeded {
+        Ok(true) => ExitCode::SUCCESS,
+        Ok(false) => ExitCode::FAILURE,
+        Err(e) => {
+            eprintln!("error: {e}");
+            ExitCode::FAILURE
+        }
+    }
+}
+
+// Load a controller, with its registers found from the text, and debug it on stdin.
+fn debug(source: &str) -> io::Result<bool> {
+    let options = MachineOptions { auto_registers: true, ..MachineOptions::default() };
+    let machine = match Machine::make_machine_with_options(&[], standard_operations(), source, options) {
+        Ok(machine) => machine,
+        Err(e) => {
+            eprint!("{}", e.render(source));
+            return Ok(false);
+        }
+    };
+    for warning in machine.warnings() {
+        eprintln!("warning: {warning}");
+    }
+    Debugger::new(machine).run(io::stdin().lock(), &mut io::stdout())?;
+    Ok(true)
 }